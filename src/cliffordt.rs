@@ -0,0 +1,304 @@
+// Approximation of single qubit gates by words in h, s and t, with the
+// Solovay-Kitaev recursion of Dawson and Nielsen on top of a brute force net.
+use float::{Float, TOL, ZERO_TOL};
+use float::consts::PI;
+use float::INFINITY;
+use linalg::adjoint;
+use qvm::{mul, Complex, Matrix, C0, C1, CI};
+use std::collections::BTreeMap;
+
+// words are in program order, so the first gate is applied first
+pub struct Approximation {
+    pub gates: Vec<&'static str>,
+    pub error: Float,
+    pub t_count: usize,
+}
+
+// clifford t clifford ... words with their gates, the base of the recursion
+pub struct Net {
+    words: Vec<(Matrix, Vec<&'static str>)>,
+}
+
+// most t gates in a net word, about 9000 distinct gates
+const NET_T_COUNT: usize = 7;
+const MAX_DEPTH: usize = 4;
+
+fn gate(name: &str) -> Matrix {
+    let h = Complex::new(1.0 / (2.0 as Float).sqrt(), 0.0);
+    match name {
+        "h" => vec![vec![h, h], vec![h, -h]],
+        "s" => vec![vec![C1, C0], vec![C0, CI]],
+        "t" => vec![vec![C1, C0], vec![C0, Complex::from_polar(&1.0, &(PI / 4.0))]],
+        _ => unreachable!(),
+    }
+}
+
+fn word_matrix(word: &[&'static str]) -> Matrix {
+    word.iter().fold(vec![vec![C1, C0], vec![C0, C1]], |acc, name| mul(&gate(name), &acc))
+}
+
+fn dagger(word: &[&'static str]) -> Vec<&'static str> {
+    // s^dagger = s s s and t^dagger = s s s t
+    let mut ret = Vec::new();
+    for &name in word.iter().rev() {
+        match name {
+            "h" => ret.push("h"),
+            "s" => ret.extend(&["s", "s", "s"]),
+            _ => ret.extend(&["s", "s", "s", "t"]),
+        }
+    }
+    simplify(&ret)
+}
+
+fn simplify(word: &[&'static str]) -> Vec<&'static str> {
+    // cancel h h and fold each run of s and t into eighth turns mod 8
+    let mut ret: Vec<&'static str> = Vec::new();
+    for &name in word {
+        if name == "h" {
+            if ret.last() == Some(&"h") {
+                ret.pop();
+            } else {
+                ret.push("h");
+            }
+            continue;
+        }
+        let mut turns = if name == "t" { 1 } else { 2 };
+        while let Some(&last) = ret.last() {
+            match last {
+                "t" => turns += 1,
+                "s" => turns += 2,
+                _ => break,
+            }
+            ret.pop();
+        }
+        turns %= 8;
+        for _ in 0..turns / 2 {
+            ret.push("s");
+        }
+        if turns % 2 == 1 {
+            ret.push("t");
+        }
+    }
+    ret
+}
+
+// (axis, angle) with u = e^(i phase) (cos(angle/2) - i sin(angle/2) axis.sigma)
+// and the angle in [0, pi]
+fn axis_angle(u: &Matrix) -> ([Float; 3], Float) {
+    let root = (u[0][0] * u[1][1] - u[0][1] * u[1][0]).sqrt();
+    let su: Vec<Vec<Complex>> = u.iter().map(|row| row.iter().map(|c| c / root).collect()).collect();
+    let mut c = (su[0][0] + su[1][1]).re / 2.0;
+    let mut v = [
+        -(su[0][1].im + su[1][0].im) / 2.0,
+        (su[1][0].re - su[0][1].re) / 2.0,
+        (su[1][1].im - su[0][0].im) / 2.0,
+    ];
+    if c < 0.0 {
+        c = -c;
+        for x in v.iter_mut() {
+            *x = -*x;
+        }
+    }
+    let s = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    if s < ZERO_TOL {
+        return ([0.0, 0.0, 1.0], 0.0);
+    }
+    ([v[0] / s, v[1] / s, v[2] / s], 2.0 * s.atan2(c))
+}
+
+fn rotation(axis: [Float; 3], angle: Float) -> Matrix {
+    let (s, c) = (angle / 2.0).sin_cos();
+    let (x, y, z) = (axis[0], axis[1], axis[2]);
+    vec![
+        vec![Complex::new(c, -s * z), Complex::new(-s * y, -s * x)],
+        vec![Complex::new(s * y, -s * x), Complex::new(c, s * z)],
+    ]
+}
+
+// operator norm distance, minimised over the global phase
+pub fn distance(u: &Matrix, v: &Matrix) -> Float {
+    let (_, angle) = axis_angle(&mul(&adjoint(u), v));
+    2.0 * (angle / 4.0).sin()
+}
+
+fn net() -> Vec<(Matrix, Vec<&'static str>)> {
+    // the 24 cliffords found breadth first, and each gate kept once up to phase
+    let key = |m: &Matrix| -> Vec<i32> {
+        let pivot = m.iter().flat_map(|row| row.iter()).find(|c| c.norm() > TOL).unwrap();
+        let phase = pivot.conj() / pivot.norm();
+        m.iter()
+            .flat_map(|row| row.iter())
+            .flat_map(|c| {
+                let c = c * phase;
+                vec![(c.re * 1e4).round() as i32, (c.im * 1e4).round() as i32]
+            })
+            .collect()
+    };
+    let mut seen = BTreeMap::new();
+    let mut cliffords = vec![(word_matrix(&[]), Vec::new())];
+    seen.insert(key(&cliffords[0].0), ());
+    let mut i = 0;
+    while i < cliffords.len() {
+        for &name in &["h", "s"] {
+            let mut word = cliffords[i].1.clone();
+            word.push(name);
+            let m = mul(&gate(name), &cliffords[i].0);
+            if seen.insert(key(&m), ()).is_none() {
+                cliffords.push((m, word));
+            }
+        }
+        i += 1;
+    }
+    let t = gate("t");
+    let mut ret = cliffords.clone();
+    let mut layer = cliffords.clone();
+    for _ in 0..NET_T_COUNT {
+        let mut next = Vec::new();
+        for (m, word) in &layer {
+            let turned = mul(&t, m);
+            for (c, tail) in &cliffords {
+                let m = mul(c, &turned);
+                if seen.insert(key(&m), ()).is_none() {
+                    let mut word = word.clone();
+                    word.push("t");
+                    word.extend(tail);
+                    next.push((m, simplify(&word)));
+                }
+            }
+        }
+        ret.extend(next.iter().cloned());
+        layer = next;
+    }
+    ret
+}
+
+fn nearest(net: &[(Matrix, Vec<&'static str>)], u: &Matrix) -> Vec<&'static str> {
+    let mut best = (INFINITY, 0);
+    for (i, (m, _)) in net.iter().enumerate() {
+        let d = distance(u, m);
+        if d < best.0 {
+            best = (d, i);
+        }
+    }
+    net[best.1].1.clone()
+}
+
+fn commutator_factors(delta: &Matrix) -> (Matrix, Matrix) {
+    // delta = v w v^dagger w^dagger with v and w rotations by equal angles
+    let (axis, angle) = axis_angle(delta);
+    let phi = 2.0 * ((1.0 - (angle / 2.0).cos()) / 2.0).powf(0.25).asin();
+    let v = rotation([1.0, 0.0, 0.0], phi);
+    let w = rotation([0.0, 1.0, 0.0], phi);
+    let group = mul(&mul(&mul(&v, &w), &adjoint(&v)), &adjoint(&w));
+    let (from, _) = axis_angle(&group);
+    // turn the commutator's axis onto delta's
+    let cross = [
+        from[1] * axis[2] - from[2] * axis[1],
+        from[2] * axis[0] - from[0] * axis[2],
+        from[0] * axis[1] - from[1] * axis[0],
+    ];
+    let sin = (cross[0] * cross[0] + cross[1] * cross[1] + cross[2] * cross[2]).sqrt();
+    let cos = from[0] * axis[0] + from[1] * axis[1] + from[2] * axis[2];
+    let turn = if sin > ZERO_TOL {
+        rotation([cross[0] / sin, cross[1] / sin, cross[2] / sin], sin.atan2(cos))
+    } else if cos > 0.0 {
+        rotation([0.0, 0.0, 1.0], 0.0)
+    } else {
+        // opposite axes, any perpendicular will do
+        let perp = if from[0].abs() < 0.9 { [0.0, -from[2], from[1]] } else { [-from[2], 0.0, from[0]] };
+        let norm = (perp[0] * perp[0] + perp[1] * perp[1] + perp[2] * perp[2]).sqrt();
+        rotation([perp[0] / norm, perp[1] / norm, perp[2] / norm], PI)
+    };
+    let conj = |m: &Matrix| mul(&mul(&turn, m), &adjoint(&turn));
+    (conj(&v), conj(&w))
+}
+
+fn solovay_kitaev(net: &[(Matrix, Vec<&'static str>)], u: &Matrix, depth: usize) -> Vec<&'static str> {
+    if depth == 0 {
+        return nearest(net, u);
+    }
+    let prev = solovay_kitaev(net, u, depth - 1);
+    let delta = mul(u, &adjoint(&word_matrix(&prev)));
+    let (v, w) = commutator_factors(&delta);
+    let v = solovay_kitaev(net, &v, depth - 1);
+    let w = solovay_kitaev(net, &w, depth - 1);
+    // v w v^dagger w^dagger prev, written in program order
+    let mut word = prev;
+    word.extend(dagger(&w));
+    word.extend(dagger(&v));
+    word.extend(w);
+    word.extend(v);
+    simplify(&word)
+}
+
+impl Net {
+    // slow enough to build that it is worth keeping, see QVM::approximate
+    pub fn new() -> Net {
+        Net { words: net() }
+    }
+    // The shortest recursion depth that reaches `accuracy`, or an error with
+    // the closest found when none does.
+    pub fn approximate(&self, u: &Matrix, accuracy: Float) -> Result<Approximation, String> {
+        let mut best: Option<Approximation> = None;
+        for depth in 0..MAX_DEPTH + 1 {
+            let gates = solovay_kitaev(&self.words, u, depth);
+            let error = distance(u, &word_matrix(&gates));
+            let t_count = gates.iter().filter(|&&name| name == "t").count();
+            let better = match best {
+                Some(ref b) => error < b.error,
+                None => true,
+            };
+            if better {
+                best = Some(Approximation { gates, error, t_count });
+            }
+            if error <= accuracy {
+                return Ok(best.unwrap());
+            }
+        }
+        let best = best.unwrap();
+        Err(format!(
+            "reached an error of {:.2e} with t-count {}, short of {:.2e}",
+            best.error, best.t_count, accuracy
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use linalg::{rx, rz};
+    use qvm::QVM;
+
+    #[test]
+    fn exact_words() {
+        assert_eq!(simplify(&["t", "t", "h", "h", "s", "t"]), vec!["s", "s", "t"]);
+        assert!(simplify(&["s", "t", "t", "s", "s", "h", "h"]).is_empty());
+        let word = ["h", "t", "s", "h", "t"];
+        let both = mul(&word_matrix(&dagger(&word)), &word_matrix(&word));
+        assert!(distance(&both, &word_matrix(&[])) < 1e-5);
+        // t itself is in the net
+        let found = Net::new().approximate(&gate("t"), 1e-3).unwrap();
+        assert_eq!(found.gates, vec!["t"]);
+        assert_eq!(found.t_count, 1);
+
+        let qvm = QVM::new();
+        let s = qvm.approximate("s", 1e-3).unwrap();
+        assert_eq!((s.gates, s.t_count), (vec!["s"], 0));
+        assert!(qvm.approximate("cnot", 1e-3).is_err());
+        assert!(qvm.approximate("rz(0.1)", 1e-2).unwrap().error <= 1e-2);
+        // past what the deepest recursion reaches
+        let err = qvm.approximate("rz(0.1)", 1e-30).err().unwrap();
+        assert!(err.starts_with("reached an error of"), "{}", err);
+    }
+    #[test]
+    fn rotations() {
+        let net = Net::new();
+        for u in &[rz(0.1), rx(1.0), mul(&rz(2.3), &rx(-0.4))] {
+            let coarse = net.approximate(u, 0.1).unwrap();
+            assert!(coarse.error <= 0.1);
+            let fine = net.approximate(u, 1e-2).unwrap();
+            assert!(fine.error <= 1e-2);
+            assert!(distance(u, &word_matrix(&fine.gates)) <= 1e-2);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+// Quantities read off the state, for watches and conditional breakpoints:
+//   P(3), P(3=0)           the probability qubit 3 reads 1, or 0
+//   P(0 1=10)              the marginal probability qubits 0 and 1 read 1 and 0
+//   amp(00000101)          the amplitude of |00000101>, phase(00000101) its argument
+//   E(Z0 Z1)               the expectation value of an observable
+//   F(ghz)                 the fidelity to a named state, see named_state
+//   concurrence(0, 1), norm and drift, which is |norm - 1|
+// and conditions on them, one per line, eg.
+//   P(3) > 0.5             qubit 3 reads 1 more than half the time
+//   amp(00000101) changes  the amplitude of |00000101> moves
+//   entangled 0 1          the pair's reduced state has non-zero concurrence
+//   drift > 1e-5           the norm is more than 1e-5 away from 1
+use float::consts::PI;
+use float::{Float, TOL};
+use qvm::{Complex, C1, NQ};
+use std::fmt;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Quantity {
+    // the probability of reading `bits` from `qubits`, the first of them
+    // being the most significant bit
+    Probability(Vec<usize>, usize),
+    Amplitude(usize),
+    Phase(usize),
+    // the observable's text, parsed when it is read
+    Expectation(String),
+    Fidelity(String),
+    Concurrence(usize, usize),
+    Norm,
+    Drift,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Test {
+    Above(Float),
+    Below(Float),
+    Changes,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Condition {
+    text: String,
+    pub quantity: Quantity,
+    test: Test,
+}
+
+fn qubit(word: &str) -> Result<usize, String> {
+    word.trim().parse().map_err(|_| format!("bad qubit '{}'", word.trim()))
+}
+
+fn basis(arg: &str) -> Result<usize, String> {
+    let bits = arg.trim().trim_start_matches('|').trim_end_matches('>');
+    match usize::from_str_radix(bits, 2) {
+        Ok(n) if bits.len() < 64 => Ok(n),
+        _ => Err(format!("bad basis state '{}'", arg.trim())),
+    }
+}
+
+// the text between "name(" and ")", eg. "3=1" from "P(3=1)"
+fn argument<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    if text.starts_with(name) && text[name.len()..].trim_start().starts_with('(') && text.ends_with(')') {
+        let open = text.find('(').unwrap();
+        Some(&text[open + 1..text.len() - 1])
+    } else {
+        None
+    }
+}
+
+// The non-zero amplitudes of "zero", "plus", "bell" (on qubits 0 and 1),
+// "ghz" or "w" on `width` qubits.
+pub fn named_state(name: &str, width: usize) -> Result<Vec<(usize, Complex)>, String> {
+    let spread = |ns: Vec<usize>| {
+        let amp = Complex::new(1.0 / (ns.len() as Float).sqrt(), 0.0);
+        ns.into_iter().map(|n| (n, amp)).collect()
+    };
+    match name {
+        "zero" => Ok(vec![(0, C1)]),
+        "plus" if width <= NQ => Ok(spread((0..1 << width).collect())),
+        "plus" => Err("plus is too wide to list past 8 qubits".into()),
+        "bell" if width >= 2 => Ok(spread(vec![0, 3])),
+        "ghz" if width < 64 => Ok(spread(vec![0, (1 << width) - 1])),
+        "w" if width < 64 => Ok(spread((0..width).map(|qb| 1 << qb).collect())),
+        _ => Err(format!("no state '{}' on {} qubits", name, width)),
+    }
+}
+
+impl Quantity {
+    pub fn parse(text: &str) -> Result<Quantity, String> {
+        let text = text.trim();
+        if let Some(arg) = argument(text, "P") {
+            let mut parts = arg.splitn(2, '=');
+            let qubits = parts
+                .next()
+                .unwrap()
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|word| !word.is_empty())
+                .map(qubit)
+                .collect::<Result<Vec<usize>, String>>()?;
+            let bits = parts.next().map(str::trim).unwrap_or("1");
+            return match usize::from_str_radix(bits, 2) {
+                Ok(n) if bits.len() == qubits.len() => Ok(Quantity::Probability(qubits, n)),
+                _ => Err(format!("bad bits '{}' for {} qubits", bits, qubits.len())),
+            };
+        }
+        if let Some(arg) = argument(text, "amp") {
+            return Ok(Quantity::Amplitude(basis(arg)?));
+        }
+        if let Some(arg) = argument(text, "phase") {
+            return Ok(Quantity::Phase(basis(arg)?));
+        }
+        if let Some(arg) = argument(text, "E") {
+            return Ok(Quantity::Expectation(arg.trim().to_string()));
+        }
+        if let Some(arg) = argument(text, "F") {
+            return Ok(Quantity::Fidelity(arg.trim().to_string()));
+        }
+        if let Some(arg) = argument(text, "concurrence") {
+            let qubits: Vec<&str> = arg.split(',').collect();
+            return match qubits.len() {
+                2 => Ok(Quantity::Concurrence(qubit(qubits[0])?, qubit(qubits[1])?)),
+                _ => Err(format!("concurrence takes two qubits, got '{}'", arg)),
+            };
+        }
+        match text {
+            "norm" => Ok(Quantity::Norm),
+            "drift" => Ok(Quantity::Drift),
+            _ => Err(format!("unknown quantity '{}'", text)),
+        }
+    }
+    // an amplitude as it is, a phase in units of pi and anything else as a number
+    pub fn fmt_value(&self, value: Complex) -> String {
+        match *self {
+            Quantity::Amplitude(_) => format!("{}", value),
+            Quantity::Phase(_) => format!("{:.4}π", value.re / PI),
+            _ => format!("{:.4}", value.re),
+        }
+    }
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        let text = text.trim();
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let (quantity, test) = if words.first() == Some(&"entangled") {
+            if words.len() != 3 {
+                return Err("entangled takes two qubits".into());
+            }
+            (Quantity::Concurrence(qubit(words[1])?, qubit(words[2])?), Test::Above(TOL))
+        } else if text.ends_with(" changes") {
+            (Quantity::parse(&text[..text.len() - " changes".len()])?, Test::Changes)
+        } else {
+            let at = text.rfind(|c: char| c == '>' || c == '<').ok_or(format!("no test in '{}'", text))?;
+            let bound = text[at + 1..].trim();
+            let bound: Float = bound.parse().map_err(|_| format!("bad number '{}'", bound))?;
+            let test = if text[at..].starts_with('>') { Test::Above(bound) } else { Test::Below(bound) };
+            (Quantity::parse(&text[..at])?, test)
+        };
+        Ok(Condition {
+            text: text.to_string(),
+            quantity,
+            test,
+        })
+    }
+    fn holds(&self, value: Complex) -> bool {
+        // every quantity but an amplitude is real, and an amplitude compares
+        // by its magnitude
+        let value = match self.quantity {
+            Quantity::Amplitude(_) => value.norm(),
+            _ => value.re,
+        };
+        match self.test {
+            Test::Above(bound) => value > bound,
+            Test::Below(bound) => value < bound,
+            Test::Changes => false,
+        }
+    }
+    // whether the condition became true over a step that took the quantity
+    // from `before` to `after`
+    pub fn triggered(&self, before: Complex, after: Complex) -> bool {
+        match self.test {
+            Test::Changes => (after - before).norm() > TOL,
+            _ => !self.holds(before) && self.holds(after),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qvm::QVM;
+
+    #[test]
+    fn parse() {
+        let p = Condition::parse("P(3) > 0.5").unwrap();
+        assert_eq!(p.quantity, Quantity::Probability(vec![3], 1));
+        assert_eq!(p.test, Test::Above(0.5));
+        assert_eq!(Condition::parse(" P( 2 = 0 ) < 1e-2").unwrap().quantity, Quantity::Probability(vec![2], 0));
+        let amp = Condition::parse("amp(|00000101>) changes").unwrap();
+        assert_eq!((amp.quantity, amp.test), (Quantity::Amplitude(5), Test::Changes));
+        assert_eq!(Quantity::parse("P(0, 1=10)").unwrap(), Quantity::Probability(vec![0, 1], 2));
+        assert_eq!(Quantity::parse("E(Z0 Z1)").unwrap(), Quantity::Expectation("Z0 Z1".into()));
+        assert_eq!(Quantity::parse("F( ghz )").unwrap(), Quantity::Fidelity("ghz".into()));
+        assert!(Quantity::parse("P(0 1)").is_err());
+        assert_eq!(Condition::parse("entangled 0 1").unwrap().quantity, Quantity::Concurrence(0, 1));
+        assert_eq!(Condition::parse("drift > 1e-5").unwrap().test, Test::Above(1e-5));
+        assert_eq!(format!("{}", Condition::parse("  norm < 0.9 ").unwrap()), "norm < 0.9");
+        assert!(Condition::parse("P(x) > 0.5").is_err());
+        assert!(Condition::parse("P(3) = 0.5").is_err());
+        assert!(Condition::parse("fidelity > 0.5").is_err());
+        assert!(Condition::parse("phase(11) < -0.5").unwrap().holds(Complex::new(-1.0, 0.0)));
+        assert!(Condition::parse("entangled 0").is_err());
+        assert_eq!(named_state("w", 3).unwrap().len(), 3);
+        // basis states past 64 qubits do not fit an index
+        assert!(named_state("w", 70).is_err());
+        assert!(named_state("ghz", 64).is_err());
+    }
+    #[test]
+    fn stops_where_it_becomes_true() {
+        let mut qvm = QVM::new();
+        assert!(qvm.update("x 2\nh 0\ncnot 0 1\nh 3\nx 3\n"));
+        qvm.set_conditions("entangled 0 1\namp(00001100) changes").unwrap();
+        assert!(qvm.resume());
+        assert_eq!((qvm.counter, qvm.triggered.clone()), (3, vec![0]));
+        assert!(qvm.resume());
+        assert_eq!((qvm.counter, qvm.triggered.clone()), (4, vec![1]));
+        // x 3 swaps the amplitude of |1100> with that of |0100>, which is the same
+        assert!(!qvm.resume());
+        qvm.reset();
+        qvm.set_conditions("P(1) > 0.4\n").unwrap();
+        assert!(qvm.resume());
+        assert_eq!(qvm.counter, 3);
+        assert!(qvm.set_conditions("P(1) > 0.4\nP(9) > 0.5").is_err());
+        assert_eq!(qvm.conditions().len(), 1);
+    }
+}
@@ -0,0 +1,181 @@
+use float::{Float, ZERO_TOL};
+use linalg::{adjoint, eigh};
+use qvm::{deposit, mul, Matrix, Qstate, C0, NQ};
+
+// pairwise entanglement between two qubits
+#[derive(Clone)]
+pub struct Link {
+    pub a: usize,
+    pub b: usize,
+    pub mutual_information: Float,
+    pub concurrence: Float,
+}
+
+pub struct Bloch {
+    pub x: Float,
+    pub y: Float,
+    pub z: Float,
+    pub purity: Float,
+}
+
+pub fn reduced_density_matrix(state: &Qstate, qubits: &[usize]) -> Matrix {
+    // partial trace over every qubit not in `qubits`
+    let dim = 1 << qubits.len();
+    let mask = deposit(dim - 1, qubits);
+    let mut rho = vec![vec![C0; dim]; dim];
+    for base in (0..state.len()).filter(|i| i & mask == 0) {
+        let amps: Vec<_> = (0..dim).map(|a| state[base | deposit(a, qubits)]).collect();
+        for (a, amp_a) in amps.iter().enumerate() {
+            for (b, amp_b) in amps.iter().enumerate() {
+                rho[a][b] += amp_a * amp_b.conj();
+            }
+        }
+    }
+    rho
+}
+
+pub fn purity(rho: &Matrix) -> Float {
+    // tr(rho^2) for hermitian rho
+    rho.iter().flat_map(|row| row.iter()).map(|c| c.norm_sqr()).sum()
+}
+
+pub fn bloch(state: &Qstate, qb: usize) -> Bloch {
+    // rho = (I + xX + yY + zZ) / 2
+    let rho = reduced_density_matrix(state, &[qb]);
+    Bloch {
+        x: 2.0 * rho[0][1].re,
+        y: -2.0 * rho[0][1].im,
+        z: rho[0][0].re - rho[1][1].re,
+        purity: purity(&rho),
+    }
+}
+
+pub fn entropy(rho: &Matrix) -> Float {
+    // von neumann entropy in bits
+    let (values, _) = eigh(rho);
+    values
+        .iter()
+        .filter(|&&p| p > ZERO_TOL)
+        .map(|p| -p * p.log2())
+        .sum()
+}
+
+pub fn entanglement_entropy(state: &Qstate, part: &[usize]) -> Float {
+    // S(A) = S(B) for a pure state, so trace down to the smaller side
+    let rest: Vec<usize> = (0..NQ).filter(|qb| !part.contains(qb)).collect();
+    if part.len() <= rest.len() {
+        entropy(&reduced_density_matrix(state, part))
+    } else {
+        entropy(&reduced_density_matrix(state, &rest))
+    }
+}
+
+fn sqrtm(rho: &Matrix) -> Matrix {
+    let (values, v) = eigh(rho);
+    let mut scaled = v.clone();
+    for row in scaled.iter_mut() {
+        for (j, item) in row.iter_mut().enumerate() {
+            *item = *item * values[j].max(0.0).sqrt();
+        }
+    }
+    mul(&scaled, &adjoint(&v))
+}
+
+pub fn concurrence(rho: &Matrix) -> Float {
+    // wootters: rho~ = (Y x Y) rho* (Y x Y), which flips both bits with sign
+    let sign = |i: usize| if i == 0 || i == 3 { 1.0 } else { -1.0 };
+    let flipped: Matrix = (0..4)
+        .map(|i| (0..4).map(|j| rho[3 - i][3 - j].conj() * sign(i) * sign(j)).collect())
+        .collect();
+    let root = sqrtm(rho);
+    let (values, _) = eigh(&mul(&mul(&root, &flipped), &root));
+    let mut lambdas: Vec<Float> = values.iter().map(|v| v.max(0.0).sqrt()).collect();
+    lambdas.sort_by(|a, b| b.partial_cmp(a).unwrap());
+    (lambdas[0] - lambdas[1] - lambdas[2] - lambdas[3]).max(0.0)
+}
+
+pub fn links(state: &Qstate) -> Vec<Link> {
+    let singles: Vec<Float> = (0..NQ)
+        .map(|qb| entropy(&reduced_density_matrix(state, &[qb])))
+        .collect();
+    let mut ret = Vec::new();
+    for a in 0..NQ {
+        for b in a + 1..NQ {
+            let rho = reduced_density_matrix(state, &[a, b]);
+            ret.push(Link {
+                a,
+                b,
+                mutual_information: (singles[a] + singles[b] - entropy(&rho)).max(0.0),
+                concurrence: concurrence(&rho),
+            });
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qvm::QVM;
+
+    fn run(prog: &str) -> QVM {
+        let mut qvm = QVM::new();
+        qvm.update(prog);
+        while qvm.counter < qvm.program.len() {
+            qvm.next();
+        }
+        qvm
+    }
+    fn close(a: Float, b: Float) -> bool {
+        (a - b).abs() < 1e-5
+    }
+    #[test]
+    fn product_state() {
+        let qvm = run("h 0\nx 1\ny 2\nh 3\nz 3");
+        let b = qvm.bloch_vectors().unwrap();
+        assert!(close(b[0].x, 1.0) && close(b[0].z, 0.0));
+        assert!(close(b[1].z, -1.0));
+        assert!(close(b[2].z, -1.0));
+        assert!(close(b[3].x, -1.0));
+        assert_eq!(b.len(), 4);
+        assert!(b.iter().all(|b| close(b.purity, 1.0)));
+    }
+    #[test]
+    fn bell_is_mixed() {
+        let qvm = run("h 0\ncnot 0 1");
+        let b = qvm.bloch_vectors().unwrap();
+        assert_eq!(b.len(), 2);
+        for qb in 0..2 {
+            assert!(close(b[qb].x, 0.0) && close(b[qb].y, 0.0) && close(b[qb].z, 0.0));
+            assert!(close(b[qb].purity, 0.5));
+        }
+        let rho = reduced_density_matrix(&qvm.state, &[1, 0]);
+        assert!(close(rho[0][3].re, 0.5) && close(rho[3][0].re, 0.5));
+        assert!(close(purity(&rho), 1.0));
+    }
+    #[test]
+    fn entanglement() {
+        let qvm = run("h 0\ncnot 0 1\nh 2\nh 3\ncnot 3 4\ncnot 4 5");
+        assert!(close(entanglement_entropy(&qvm.state, &[0]), 1.0));
+        assert!(close(entanglement_entropy(&qvm.state, &[0, 1]), 0.0));
+        assert!(close(entanglement_entropy(&qvm.state, &[0, 2, 3]), 2.0));
+        assert!(close(entanglement_entropy(&qvm.state, &[1, 2, 3, 6, 7]), 2.0));
+        assert!(close(qvm.entanglement_entropy(&[0, 2, 3]).unwrap(), 2.0));
+        assert!(qvm.entanglement_entropy(&[9]).is_err());
+        assert!(qvm.entanglement_entropy(&[0, 0]).is_err());
+        let links = qvm.entanglement_links().unwrap();
+        let link = |a, b| links.iter().find(|l| l.a == a && l.b == b).unwrap();
+        assert!(close(link(0, 1).mutual_information, 2.0));
+        assert!(close(link(0, 1).concurrence, 1.0));
+        // ghz pairs are classically correlated but not entangled
+        assert!(close(link(3, 5).mutual_information, 1.0));
+        assert!(close(link(3, 5).concurrence, 0.0));
+        assert!(close(link(1, 2).mutual_information, 0.0));
+        // past 8 qubits there is no dense state to read them from
+        let wide = run("h 0\ncnot 0 9");
+        assert!(wide.bloch_vectors().is_err());
+        assert!(wide.entanglement_entropy(&[0]).is_err());
+        assert!(wide.expectation("Z0 Z9").is_err());
+        assert!(wide.entanglement_links().is_err());
+    }
+}
@@ -0,0 +1,306 @@
+// Exact amplitudes for clifford+t circuits in the ring Z[1/√2, i]. Each value
+// is (a + b w + c w^2 + d w^3) / √2^k with w = e^(iπ/4), kept with the smallest
+// k, so two values are equal exactly when their parts are.
+use float::consts::FRAC_PI_4;
+use float::Float;
+use qvm::{deposit, Complex, C0};
+use std::fmt;
+use std::ops::{Add, Mul, Neg, Sub};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Exact {
+    w: [i64; 4],
+    k: u32,
+}
+
+pub type ExactMatrix = Vec<Vec<Exact>>;
+
+pub const ZERO: Exact = Exact { w: [0, 0, 0, 0], k: 0 };
+pub const ONE: Exact = Exact { w: [1, 0, 0, 0], k: 0 };
+
+// multiplies the numerator by w, using w^4 = -1
+fn omega(w: [i64; 4]) -> [i64; 4] {
+    [-w[3], w[0], w[1], w[2]]
+}
+
+// multiplies the numerator by √2 = w - w^3
+fn sqrt2(w: [i64; 4]) -> [i64; 4] {
+    let once = omega(w);
+    let thrice = omega(omega(once));
+    [once[0] - thrice[0], once[1] - thrice[1], once[2] - thrice[2], once[3] - thrice[3]]
+}
+
+impl Exact {
+    pub fn new(w: [i64; 4], k: u32) -> Exact {
+        Exact { w, k }.reduced()
+    }
+    // w^n / √2^k
+    pub fn omega(n: usize, k: u32) -> Exact {
+        let mut w = [1, 0, 0, 0];
+        for _ in 0..n % 8 {
+            w = omega(w);
+        }
+        Exact::new(w, k)
+    }
+    fn reduced(self) -> Exact {
+        if self.w == [0; 4] {
+            return ZERO;
+        }
+        let mut ret = self;
+        while ret.k > 0 {
+            // x / √2^k = (x √2 / 2) / √2^(k - 1) whenever x √2 is even
+            let doubled = sqrt2(ret.w);
+            if doubled.iter().any(|c| c % 2 != 0) {
+                break;
+            }
+            ret.w = [doubled[0] / 2, doubled[1] / 2, doubled[2] / 2, doubled[3] / 2];
+            ret.k -= 1;
+        }
+        ret
+    }
+    // the numerator over √2^k for some k at least self.k
+    fn over(self, k: u32) -> [i64; 4] {
+        (self.k..k).fold(self.w, |w, _| sqrt2(w))
+    }
+    pub fn is_zero(self) -> bool {
+        self == ZERO
+    }
+    pub fn conj(self) -> Exact {
+        // w^-j = -w^(4 - j)
+        Exact::new([self.w[0], -self.w[3], -self.w[2], -self.w[1]], self.k)
+    }
+    pub fn to_complex(self) -> Complex {
+        let scale = (2.0 as Float).sqrt().powi(-(self.k as i32));
+        (0..4).fold(C0, |acc, j| acc + Complex::from_polar(&(self.w[j] as Float), &(j as Float * FRAC_PI_4)))
+            * scale
+    }
+}
+
+impl Add for Exact {
+    type Output = Exact;
+    fn add(self, other: Exact) -> Exact {
+        let k = self.k.max(other.k);
+        let (a, b) = (self.over(k), other.over(k));
+        Exact::new([a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3]], k)
+    }
+}
+
+impl Neg for Exact {
+    type Output = Exact;
+    fn neg(self) -> Exact {
+        Exact::new([-self.w[0], -self.w[1], -self.w[2], -self.w[3]], self.k)
+    }
+}
+
+impl Sub for Exact {
+    type Output = Exact;
+    fn sub(self, other: Exact) -> Exact {
+        self + -other
+    }
+}
+
+impl Mul for Exact {
+    type Output = Exact;
+    fn mul(self, other: Exact) -> Exact {
+        let mut w = [0; 4];
+        let mut shifted = other.w;
+        for j in 0..4 {
+            for i in 0..4 {
+                w[i] += self.w[j] * shifted[i];
+            }
+            shifted = omega(shifted);
+        }
+        Exact::new(w, self.k + other.k)
+    }
+}
+
+// u + v√2, eg. "1-√2"
+fn fmt_surd(u: i64, v: i64) -> String {
+    let root = match v {
+        1 => "√2".to_string(),
+        -1 => "-√2".to_string(),
+        v => format!("{}√2", v),
+    };
+    match (u, v) {
+        (u, 0) => u.to_string(),
+        (0, _) => root,
+        (u, v) if v > 0 => format!("{}+{}", u, root),
+        (u, _) => format!("{}{}", u, root),
+    }
+}
+
+impl fmt::Display for Exact {
+    // a closed form, eg. "1/√2", "-i/2" or "(1+i)/2√2"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (a, b, c, d) = (self.w[0], self.w[1], self.w[2], self.w[3]);
+        // w = (1 + i)/√2 and w^3 = (-1 + i)/√2, so the real and imaginary parts
+        // are u + v√2 over √2^m
+        let (re, im, m) = if b == 0 && d == 0 {
+            ((a, 0), (c, 0), self.k)
+        } else {
+            ((b - d, a), (b + d, c), self.k + 1)
+        };
+        let parts = [re.0, re.1, im.0, im.1].iter().filter(|&&p| p != 0).count();
+        let imag = match (fmt_surd(im.0, im.1).as_str(), im.1) {
+            ("1", _) => "i".to_string(),
+            ("-1", _) => "-i".to_string(),
+            (s, 0) => format!("{}i", s),
+            (_, _) if im.0 < 0 && im.1 < 0 => format!("-({})i", fmt_surd(-im.0, -im.1)),
+            (s, _) if im.0 != 0 => format!("({})i", s),
+            (s, _) => format!("{}i", s),
+        };
+        let numerator = match (re, im) {
+            ((0, 0), (0, 0)) => "0".to_string(),
+            (_, (0, 0)) => fmt_surd(re.0, re.1),
+            ((0, 0), _) => imag,
+            _ if imag.starts_with('-') => format!("{}{}", fmt_surd(re.0, re.1), imag),
+            _ => format!("{}+{}", fmt_surd(re.0, re.1), imag),
+        };
+        if m == 0 {
+            return write!(f, "{}", numerator);
+        }
+        let numerator = if parts > 1 { format!("({})", numerator) } else { numerator };
+        let whole = 1i64 << (m / 2);
+        let denominator = match (whole, m % 2) {
+            (1, _) => "√2".to_string(),
+            (w, 0) => w.to_string(),
+            (w, _) => format!("{}√2", w),
+        };
+        write!(f, "{}/{}", numerator, denominator)
+    }
+}
+
+// the builtin clifford+t gates, as in qvm::standard_gates
+pub fn gate(name: &str) -> Option<ExactMatrix> {
+    let (o, l) = (ZERO, ONE);
+    let h = Exact::new([1, 0, 0, 0], 1);
+    let i = Exact::omega(2, 0);
+    Some(match name {
+        "x" => vec![vec![o, l], vec![l, o]],
+        "y" => vec![vec![o, -i], vec![i, o]],
+        "z" => vec![vec![l, o], vec![o, -l]],
+        "h" => vec![vec![h, h], vec![h, -h]],
+        "s" => vec![vec![l, o], vec![o, i]],
+        "t" => vec![vec![l, o], vec![o, Exact::omega(1, 0)]],
+        "sx" => {
+            let (p, m) = (Exact::new([1, 0, 1, 0], 2), Exact::new([1, 0, -1, 0], 2));
+            vec![vec![p, m], vec![m, p]]
+        }
+        "i1" => identity(2),
+        "i2" => identity(4),
+        "cnot" => controlled(&gate("x").unwrap()),
+        "cz" => controlled(&gate("z").unwrap()),
+        "swap" => {
+            let mut swap = identity(4);
+            swap.swap(1, 2);
+            swap
+        }
+        _ => return None,
+    })
+}
+
+pub fn identity(dim: usize) -> ExactMatrix {
+    (0..dim).map(|r| (0..dim).map(|c| if r == c { ONE } else { ZERO }).collect()).collect()
+}
+
+pub fn adjoint(m: &ExactMatrix) -> ExactMatrix {
+    (0..m.len()).map(|i| (0..m.len()).map(|j| m[j][i].conj()).collect()).collect()
+}
+
+pub fn mul(a: &ExactMatrix, b: &ExactMatrix) -> ExactMatrix {
+    (0..a.len())
+        .map(|i| (0..a.len()).map(|j| (0..a.len()).fold(ZERO, |acc, k| acc + a[i][k] * b[k][j])).collect())
+        .collect()
+}
+
+// the control is the most significant, ie. first listed, qubit
+pub fn controlled(gate: &ExactMatrix) -> ExactMatrix {
+    let dim = gate.len();
+    let mut ret = identity(2 * dim);
+    for (i, row) in gate.iter().enumerate() {
+        for (j, item) in row.iter().enumerate() {
+            ret[dim + i][dim + j] = *item;
+        }
+    }
+    ret
+}
+
+// like qvm::apply_gate, on exact amplitudes
+pub fn apply_gate(gate: &ExactMatrix, qubits: &[usize], state: &[Exact]) -> Vec<Exact> {
+    let dim = gate.len();
+    let offsets: Vec<usize> = (0..dim).map(|local| deposit(local, qubits)).collect();
+    let mask = offsets[dim - 1];
+    let mut ret = state.to_vec();
+    for base in (0..state.len()).filter(|i| i & mask == 0) {
+        for (r, row) in gate.iter().enumerate() {
+            ret[base | offsets[r]] = row
+                .iter()
+                .enumerate()
+                .fold(ZERO, |acc, (c, item)| acc + *item * state[base | offsets[c]]);
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qvm::{parse_program, Equivalence, C1, QVM};
+
+    #[test]
+    fn closed_forms() {
+        let h = Exact::new([1, 0, 0, 0], 1);
+        assert_eq!(h * h + h * h, ONE);
+        assert_eq!(Exact::omega(1, 0) * Exact::omega(7, 0), ONE);
+        assert_eq!(Exact::new([2, 0, 0, 0], 2), ONE);
+        assert_eq!(format!("{}", h), "1/√2");
+        assert_eq!(format!("{}", -h * h), "-1/2");
+        assert_eq!(format!("{}", Exact::omega(1, 2)), "(1+i)/2√2");
+        assert_eq!(format!("{}", Exact::omega(2, 2)), "i/2");
+        assert_eq!(format!("{}", Exact::omega(3, 0)), "(-1+i)/√2");
+        assert_eq!(format!("{}", ONE + h), "(2+√2)/2");
+        assert_eq!(format!("{}", Exact::new([0, 1, 0, 1], 0) * h), "i");
+        assert_eq!(format!("{}", ZERO), "0");
+        let value = Exact::new([3, -1, 2, 5], 3);
+        assert!((value.to_complex() - Complex::new(-0.43934, 1.70711)).norm() < 1e-4);
+        assert!((value.conj().to_complex() - value.to_complex().conj()).norm() < 1e-5);
+    }
+    #[test]
+    fn matches_dense() {
+        let mut qvm = QVM::new();
+        assert!(qvm.update("h 0\nt 0\ncnot 0 1\nh 1\ndagger t 1\nctrl @ s 1 2\nsx 2\nh 0\n"));
+        while qvm.counter < qvm.program.len() {
+            qvm.next();
+        }
+        let exact = qvm.exact_state().unwrap();
+        for (n, c) in qvm.state.iter().enumerate() {
+            assert!((exact[n].to_complex() - c).norm() < 1e-5, "{} vs {} at {}", exact[n], c, n);
+        }
+        let forms: Vec<String> = exact.iter().filter(|c| !c.is_zero()).map(|c| format!("{}", c)).collect();
+        assert_eq!(forms[0], "(1+(1+√2)i)/4√2");
+        assert_eq!(forms[7], "(1-(1+√2)i)/4√2");
+        assert!(qvm.update("h 0\nrx(0.1) 0\n"));
+        assert!(qvm.exact_state().is_err());
+    }
+    #[test]
+    fn exact_equivalence() {
+        let mut qvm = QVM::new();
+        assert!(qvm.update("t 1\nt 1\nh 0\ncz 0 1\nh 0\n"));
+        let other = parse_program("s 1\ncnot 1 0\n").unwrap();
+        match qvm.compare_exact(&other).unwrap() {
+            Equivalence::Same { phase } => assert!((phase - C1).norm() < 1e-5),
+            _ => panic!("t t is s and h cz h is cnot"),
+        }
+        let other = parse_program("pow(3) @ t 1\ncnot 1 0\n").unwrap();
+        match qvm.compare_exact(&other).unwrap() {
+            Equivalence::Differs { input, .. } => assert_eq!(input, 2),
+            _ => panic!("t t t is not s"),
+        }
+        assert!(qvm.compare_exact(&parse_program("ry(0.5) 0\n").unwrap()).is_err());
+        match qvm.compare_exact(&parse_program("h 0\nmeasure 0\n").unwrap()) {
+            Err(e) => assert!(e.starts_with("line 2"), "{}", e),
+            Ok(_) => panic!("a measurement has no unitary"),
+        }
+        assert!(qvm.compare_exact(&parse_program("cnot 0 9\n").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,23 @@
+// The float type everything is computed in: f32, or f64 with the "f64" feature
+// for long programs where f32 rounding shows in the amplitudes.
+#[cfg(not(feature = "f64"))]
+pub use std::f32::{consts, INFINITY};
+#[cfg(feature = "f64")]
+pub use std::f64::{consts, INFINITY};
+
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(feature = "f64")]
+pub type Float = f64;
+
+// entries below this are zero, a few hundred gates' worth of rounding
+#[cfg(not(feature = "f64"))]
+pub const ZERO_TOL: Float = 1e-6;
+#[cfg(feature = "f64")]
+pub const ZERO_TOL: Float = 1e-12;
+
+// agreement threshold for results accumulated over a whole program
+#[cfg(not(feature = "f64"))]
+pub const TOL: Float = 1e-4;
+#[cfg(feature = "f64")]
+pub const TOL: Float = 1e-9;
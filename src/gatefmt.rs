@@ -0,0 +1,248 @@
+// Plain text gate format, one block per gate:
+//
+//     h:
+//     1/sqrt(2), 1/sqrt(2)
+//     1/sqrt(2), -1/sqrt(2)
+//
+// Entries are complex expressions such as 0.707, -i, 0.5+0.5i or exp(i*pi/4).
+use float::{Float, ZERO_TOL};
+use float::consts::{FRAC_1_SQRT_2, PI};
+use qvm::{Complex, Matrix, CI};
+use std::collections::BTreeMap;
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&mut self) -> Option<char> {
+        self.text[self.pos..].chars().find(|c| !c.is_whitespace())
+    }
+    fn bump(&mut self) -> Option<char> {
+        let skipped = self.text[self.pos..].len() - self.text[self.pos..].trim_start().len();
+        self.pos += skipped;
+        let c = self.text[self.pos..].chars().next();
+        if let Some(c) = c {
+            self.pos += c.len_utf8();
+        }
+        c
+    }
+    fn expect(&mut self, want: char) -> Result<(), String> {
+        match self.bump() {
+            Some(c) if c == want => Ok(()),
+            _ => Err(format!("expected '{}' in '{}'", want, self.text)),
+        }
+    }
+    fn expr(&mut self) -> Result<Complex, String> {
+        let mut val = self.term()?;
+        loop {
+            match self.peek() {
+                Some('+') => {
+                    self.bump();
+                    val = val + self.term()?;
+                }
+                Some('-') => {
+                    self.bump();
+                    val = val - self.term()?;
+                }
+                _ => return Ok(val),
+            }
+        }
+    }
+    fn term(&mut self) -> Result<Complex, String> {
+        let mut val = self.factor()?;
+        loop {
+            match self.peek() {
+                Some('*') => {
+                    self.bump();
+                    val = val * self.factor()?;
+                }
+                Some('/') => {
+                    self.bump();
+                    val = val / self.factor()?;
+                }
+                _ => return Ok(val),
+            }
+        }
+    }
+    fn factor(&mut self) -> Result<Complex, String> {
+        match self.peek() {
+            Some('-') => {
+                self.bump();
+                Ok(-self.factor()?)
+            }
+            Some('+') => {
+                self.bump();
+                self.factor()
+            }
+            Some('(') => {
+                self.bump();
+                let val = self.expr()?;
+                self.expect(')')?;
+                Ok(val)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                self.bump();
+                let start = self.pos - 1;
+                while let Some(c) = self.text[self.pos..].chars().next() {
+                    if !(c.is_ascii_digit() || c == '.') {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+                let number: Float = self.text[start..self.pos]
+                    .parse()
+                    .map_err(|_| format!("bad number '{}'", &self.text[start..self.pos]))?;
+                // 0.5i is an imaginary literal
+                if self.text[self.pos..].starts_with('i') {
+                    self.pos += 1;
+                    Ok(CI * number)
+                } else {
+                    Ok(Complex::new(number, 0.0))
+                }
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                self.bump();
+                let start = self.pos - 1;
+                while let Some(c) = self.text[self.pos..].chars().next() {
+                    if !c.is_ascii_alphabetic() {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+                match &self.text[start..self.pos] {
+                    "i" => Ok(CI),
+                    "pi" => Ok(Complex::new(PI, 0.0)),
+                    "sqrt" => Ok(self.argument()?.sqrt()),
+                    "exp" => Ok(self.argument()?.exp()),
+                    word => Err(format!("unknown name '{}'", word)),
+                }
+            }
+            _ => Err(format!("unexpected end of '{}'", self.text)),
+        }
+    }
+    fn argument(&mut self) -> Result<Complex, String> {
+        self.expect('(')?;
+        let val = self.expr()?;
+        self.expect(')')?;
+        Ok(val)
+    }
+}
+
+pub fn parse_complex(text: &str) -> Result<Complex, String> {
+    let mut parser = Parser { text, pos: 0 };
+    let val = parser.expr()?;
+    match parser.peek() {
+        None => Ok(val),
+        Some(c) => Err(format!("unexpected '{}' in '{}'", c, text)),
+    }
+}
+
+pub fn parse_gates(text: &str) -> Result<BTreeMap<String, Matrix>, String> {
+    let mut gates = BTreeMap::new();
+    let mut current: Option<(String, Matrix)> = None;
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if line.ends_with(':') {
+            if let Some((name, gate)) = current.take() {
+                gates.insert(name, gate);
+            }
+            current = Some((line[..line.len() - 1].trim().to_string(), Vec::new()));
+            continue;
+        }
+        match current {
+            Some((ref name, ref mut gate)) => {
+                let row: Result<Vec<Complex>, String> = line
+                    .split(',')
+                    .map(|entry| parse_complex(entry).map_err(|e| format!("gate '{}': {}", name, e)))
+                    .collect();
+                gate.push(row?);
+            }
+            None => return Err(format!("row '{}' before any 'name:' line", line)),
+        }
+    }
+    if let Some((name, gate)) = current.take() {
+        gates.insert(name, gate);
+    }
+    Ok(gates)
+}
+
+fn fmt_real(x: Float) -> String {
+    // shortest form, with 1/sqrt(2) recognised since it is everywhere
+    let sign = if x < 0.0 { "-" } else { "" };
+    if (x.abs() - FRAC_1_SQRT_2).abs() < ZERO_TOL {
+        return format!("{}1/sqrt(2)", sign);
+    }
+    // every digit needed to read the same value back
+    format!("{}{}", sign, x.abs())
+}
+
+pub fn fmt_complex(c: Complex) -> String {
+    let re = if c.re.abs() < ZERO_TOL { 0.0 } else { c.re };
+    let im = if c.im.abs() < ZERO_TOL { 0.0 } else { c.im };
+    let imag = |im: Float| match fmt_real(im.abs()).as_str() {
+        "1" => "i".to_string(),
+        "1/sqrt(2)" => "i/sqrt(2)".to_string(),
+        text => format!("{}i", text),
+    };
+    if im == 0.0 {
+        fmt_real(re)
+    } else if re == 0.0 {
+        format!("{}{}", if im < 0.0 { "-" } else { "" }, imag(im))
+    } else {
+        format!("{}{}{}", fmt_real(re), if im < 0.0 { "-" } else { "+" }, imag(im))
+    }
+}
+
+pub fn fmt_gates(gates: &BTreeMap<String, Matrix>) -> String {
+    let mut blocks = Vec::new();
+    for (name, gate) in gates {
+        let rows: Vec<String> = gate
+            .iter()
+            .map(|row| row.iter().map(|c| fmt_complex(*c)).collect::<Vec<_>>().join(", "))
+            .collect();
+        blocks.push(format!("{}:\n{}\n", name, rows.join("\n")));
+    }
+    blocks.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Complex, b: Complex) -> bool {
+        (a - b).norm() < 1e-6
+    }
+    #[test]
+    fn entries() {
+        assert!(close(parse_complex("0.707").unwrap(), Complex::new(0.707, 0.0)));
+        assert!(close(parse_complex("-i").unwrap(), -CI));
+        assert!(close(parse_complex("1/sqrt(2)").unwrap(), Complex::new(FRAC_1_SQRT_2, 0.0)));
+        assert!(close(parse_complex("0.5+0.5i").unwrap(), Complex::new(0.5, 0.5)));
+        assert!(close(parse_complex(" -0.5 - i / 2 ").unwrap(), Complex::new(-0.5, -0.5)));
+        assert!(close(parse_complex("exp(i*pi/4)").unwrap(), Complex::new(FRAC_1_SQRT_2, FRAC_1_SQRT_2)));
+        assert!(parse_complex("1 +").is_err());
+        assert!(parse_complex("2x").is_err());
+        assert_eq!(fmt_complex(Complex::new(FRAC_1_SQRT_2, -FRAC_1_SQRT_2)), "1/sqrt(2)-i/sqrt(2)");
+        assert_eq!(fmt_complex(-CI), "-i");
+        assert_eq!(fmt_complex(Complex::new(0.25, 1.5)), "0.25+1.5i");
+    }
+    #[test]
+    fn round_trip() {
+        let text = "h:\n1/sqrt(2), 1/sqrt(2)\n1/sqrt(2), -1/sqrt(2)\n\ns:\n1, 0\n0, i\n";
+        let gates = parse_gates(text).unwrap();
+        assert_eq!(gates["s"][1][1], CI);
+        assert_eq!(fmt_gates(&gates), text);
+        // entries are printed to the last digit, so re-saving changes nothing
+        let ry = vec![
+            vec![Complex::new(0.8253356, 0.0), Complex::new(-0.5646425, 0.0)],
+            vec![Complex::new(0.5646425, 0.0), Complex::new(0.8253356, 0.0)],
+        ];
+        let mut gates = BTreeMap::new();
+        gates.insert("ry".to_string(), ry.clone());
+        assert_eq!(parse_gates(&fmt_gates(&gates)).unwrap()["ry"], ry);
+        assert_eq!(fmt_complex(Complex::new(0.1234567, 10.0)), "0.1234567+10i");
+        assert!(parse_gates("1, 0").is_err());
+        assert!(parse_gates("g:\n1, q").unwrap_err().contains("gate 'g'"));
+    }
+}
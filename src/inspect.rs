@@ -0,0 +1,64 @@
+use linalg::{eig_normal, euler_zyz, Euler};
+use pauli::decompose;
+use qvm::{Complex, Matrix};
+
+pub struct Inspection {
+    pub matrix: Matrix,
+    // eigenvalue and eigenvector pairs
+    pub eigen: Vec<(Complex, Vec<Complex>)>,
+    // pauli strings in ket order over the gate's own qubits, first operand leftmost
+    pub paulis: Vec<(Complex, String)>,
+    // only for single qubit gates
+    pub euler: Option<Euler>,
+}
+
+pub fn inspect(gate: &Matrix) -> Inspection {
+    let dim = gate.len();
+    let n = dim.trailing_zeros() as usize;
+    let (values, vectors) = eig_normal(gate);
+    let eigen = values
+        .into_iter()
+        .enumerate()
+        .map(|(j, value)| (value, (0..dim).map(|i| vectors[i][j]).collect()))
+        .collect();
+    let paulis = decompose(gate)
+        .into_iter()
+        .map(|(coeff, string)| (coeff, string.dense(n)))
+        .collect();
+    Inspection {
+        matrix: gate.clone(),
+        eigen,
+        paulis,
+        euler: if dim == 2 { Some(euler_zyz(gate)) } else { None },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use float::consts::PI;
+    use float::Float;
+    use qvm::{C1, QVM};
+
+    #[test]
+    fn builtins() {
+        let qvm = QVM::new();
+        let x = qvm.inspect("x").unwrap();
+        let euler = x.euler.unwrap();
+        assert!((euler.theta - PI).abs() < 1e-5);
+        let mut values: Vec<Float> = x.eigen.iter().map(|e| e.0.re).collect();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert!((values[0] + 1.0).abs() < 1e-5 && (values[1] - 1.0).abs() < 1e-5);
+        assert_eq!(x.paulis.len(), 1);
+        assert_eq!(x.paulis[0].1, "X");
+
+        let z = qvm.inspect("z").unwrap().euler.unwrap();
+        assert!(z.theta.abs() < 1e-5 && ((z.phi + z.lambda).abs() - PI).abs() < 1e-5);
+
+        let swap = qvm.inspect("swap").unwrap();
+        assert!(swap.euler.is_none());
+        let minus = swap.eigen.iter().filter(|e| (e.0 + C1).norm() < 1e-4).count();
+        assert_eq!(minus, 1);
+        assert_eq!(swap.paulis.len(), 4);
+        assert!(qvm.inspect("nope").is_err());
+    }
+}
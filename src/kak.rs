@@ -0,0 +1,295 @@
+// Two qubit synthesis by KAK (Cartan) decomposition:
+//
+//     U = e^(i phase) (A1 x B1) exp(i(a XX + b YY + c ZZ)) (A0 x B0)
+//
+// and the canonical middle costs three cnots (Vatan and Williams).
+use float::{Float, ZERO_TOL};
+use float::consts::{FRAC_PI_2, PI};
+use linalg::{adjoint, det, eig_commuting, euler_zyz};
+use qvm::{mul, Complex, Matrix, C0, C1, CI};
+
+pub struct Kak {
+    // local gates applied first, on the (first, second) operand
+    pub before: (Matrix, Matrix),
+    pub a: Float,
+    pub b: Float,
+    pub c: Float,
+    pub after: (Matrix, Matrix),
+}
+
+fn scale(m: &Matrix, by: Complex) -> Matrix {
+    m.iter().map(|row| row.iter().map(|c| c * by).collect()).collect()
+}
+
+fn transpose(m: &Matrix) -> Matrix {
+    (0..m.len()).map(|i| m.iter().map(|row| row[i]).collect()).collect()
+}
+
+fn magic() -> Matrix {
+    // columns are the magic basis, where local gates become real rotations
+    let h = Complex::new(1.0 / (2.0 as Float).sqrt(), 0.0);
+    let rows = vec![
+        vec![C1, C0, C0, CI],
+        vec![C0, CI, C1, C0],
+        vec![C0, CI, -C1, C0],
+        vec![C1, C0, C0, -CI],
+    ];
+    scale(&rows, h)
+}
+
+pub fn kron(a: &Matrix, b: &Matrix) -> Matrix {
+    (0..4)
+        .map(|i| (0..4).map(|j| a[i / 2][j / 2] * b[i % 2][j % 2]).collect())
+        .collect()
+}
+
+pub fn split_kron(k: &Matrix) -> (Matrix, Matrix) {
+    // k = a x b, with b taken from the largest 2x2 block and made special unitary
+    let block = |i: usize, j: usize| -> Matrix {
+        (0..2)
+            .map(|r| (0..2).map(|c| k[2 * i + r][2 * j + c]).collect())
+            .collect()
+    };
+    let weight = |m: &Matrix| -> Float { m.iter().flat_map(|row| row.iter()).map(|c| c.norm_sqr()).sum() };
+    let mut best = (0, 0);
+    for i in 0..2 {
+        for j in 0..2 {
+            if weight(&block(i, j)) > weight(&block(best.0, best.1)) {
+                best = (i, j);
+            }
+        }
+    }
+    let big = block(best.0, best.1);
+    let b = scale(&big, C1 / det(&big).sqrt());
+    let a = (0..2)
+        .map(|i| {
+            (0..2)
+                .map(|j| {
+                    let blk = block(i, j);
+                    let mut sum = C0;
+                    for r in 0..2 {
+                        for c in 0..2 {
+                            sum += b[r][c].conj() * blk[r][c];
+                        }
+                    }
+                    sum / 2.0
+                })
+                .collect()
+        })
+        .collect();
+    (a, b)
+}
+
+fn real_basis(v: &Matrix) -> Matrix {
+    // The eigenvectors come back with arbitrary phases, but the real and
+    // imaginary parts of each are eigenvectors too, so orthonormalise those.
+    let dim = v.len();
+    let mut candidates: Vec<Vec<Float>> = Vec::new();
+    for part in 0..2 {
+        for j in 0..dim {
+            let big = (0..dim).map(|i| v[i][j]).fold(C0, |a, b| if b.norm() > a.norm() { b } else { a });
+            let phase = big.conj() / big.norm();
+            candidates.push((0..dim).map(|i| if part == 0 { (v[i][j] * phase).re } else { (v[i][j] * phase).im }).collect());
+        }
+    }
+    let mut basis: Vec<Vec<Float>> = Vec::new();
+    for mut x in candidates {
+        for e in &basis {
+            let overlap: Float = x.iter().zip(e.iter()).map(|(a, b)| a * b).sum();
+            for (xi, ei) in x.iter_mut().zip(e.iter()) {
+                *xi -= overlap * ei;
+            }
+        }
+        let norm = x.iter().map(|a| a * a).sum::<Float>().sqrt();
+        if norm > 0.1 && basis.len() < dim {
+            basis.push(x.iter().map(|a| a / norm).collect());
+        }
+    }
+    (0..dim).map(|i| basis.iter().map(|e| Complex::new(e[i], 0.0)).collect()).collect()
+}
+
+pub fn kak(u: &Matrix) -> Kak {
+    let m = magic();
+    let special = scale(u, C1 / det(u).powf(0.25));
+    let ub = mul(&mul(&adjoint(&m), &special), &m);
+    // ub^T ub is symmetric unitary, so its real and imaginary parts commute
+    // and share a real orthogonal set of eigenvectors
+    let m2 = mul(&transpose(&ub), &ub);
+    let re: Matrix = m2.iter().map(|row| row.iter().map(|c| Complex::new(c.re, 0.0)).collect()).collect();
+    let im: Matrix = m2.iter().map(|row| row.iter().map(|c| Complex::new(c.im, 0.0)).collect()).collect();
+    let mut p = real_basis(&eig_commuting(&re, &im));
+    if det(&p).re < 0.0 {
+        for row in p.iter_mut() {
+            row[0] = -row[0];
+        }
+    }
+    let d = mul(&mul(&transpose(&p), &m2), &p);
+    let mut theta: Vec<Float> = (0..4).map(|k| d[k][k].arg() / 2.0).collect();
+    // the square roots must multiply to 1 for k1 to stay special orthogonal
+    let total: Float = theta.iter().sum();
+    if (total / PI).round() as i32 % 2 != 0 {
+        theta[0] += PI;
+    }
+    let mut inv_root = vec![vec![C0; 4]; 4];
+    for k in 0..4 {
+        inv_root[k][k] = Complex::from_polar(&1.0, &-theta[k]);
+    }
+    let k1 = mul(&mul(&ub, &p), &inv_root);
+    let k2 = transpose(&p);
+    let after = split_kron(&mul(&mul(&m, &k1), &adjoint(&m)));
+    let before = split_kron(&mul(&mul(&m, &k2), &adjoint(&m)));
+
+    // theta_k = phase + a xx_k + b yy_k + c zz_k, where xx_k, yy_k, zz_k = +-1
+    // are the eigenvalues of XX, YY, ZZ on the magic basis
+    let x = vec![vec![C0, C1], vec![C1, C0]];
+    let y = vec![vec![C0, -CI], vec![CI, C0]];
+    let z = vec![vec![C1, C0], vec![C0, -C1]];
+    let signs: Vec<Vec<Float>> = [x, y, z]
+        .iter()
+        .map(|p| {
+            let diag = mul(&mul(&adjoint(&m), &kron(p, p)), &m);
+            (0..4).map(|k| diag[k][k].re).collect()
+        })
+        .collect();
+    let solve = |s: &Vec<Float>| (0..4).map(|k| theta[k] * s[k]).sum::<Float>() / 4.0;
+    Kak {
+        before,
+        a: solve(&signs[0]),
+        b: solve(&signs[1]),
+        c: solve(&signs[2]),
+        after,
+    }
+}
+
+// one step of the synthesized circuit, qubit 0 being the first operand
+pub enum Step {
+    Local(Matrix, usize),
+    Rz(Float, usize),
+    Ry(Float, usize),
+    Cnot(usize, usize),
+}
+
+// Three cnots and single qubit gates equal to `u` up to global phase.
+pub fn circuit(u: &Matrix) -> Vec<Step> {
+    let kak = kak(u);
+    vec![
+        Step::Local(kak.before.0, 0),
+        Step::Local(kak.before.1, 1),
+        // exp(i(a XX + b YY + c ZZ))
+        Step::Rz(-FRAC_PI_2, 1),
+        Step::Cnot(1, 0),
+        Step::Rz(FRAC_PI_2 - 2.0 * kak.c, 0),
+        Step::Ry(2.0 * kak.a - FRAC_PI_2, 1),
+        Step::Cnot(0, 1),
+        Step::Ry(FRAC_PI_2 - 2.0 * kak.b, 1),
+        Step::Cnot(1, 0),
+        Step::Rz(FRAC_PI_2, 0),
+        Step::Local(kak.after.0, 0),
+        Step::Local(kak.after.1, 1),
+    ]
+}
+
+fn local(gate: &Matrix, qb: &str, lines: &mut Vec<String>) {
+    // zyz euler angles, dropping rotations that are only a global phase
+    let e = euler_zyz(gate);
+    let turns = if e.theta.abs() < ZERO_TOL {
+        vec![("rz", e.lambda + e.phi)]
+    } else {
+        vec![("rz", e.lambda), ("ry", e.theta), ("rz", e.phi)]
+    };
+    for (axis, turn) in turns {
+        let wrapped = turn - 2.0 * PI * (turn / (2.0 * PI)).round();
+        if wrapped.abs() > ZERO_TOL {
+            lines.push(format!("{}({}) {}", axis, turn, qb));
+        }
+    }
+}
+
+// Program text equivalent to `u` on qubits (qa, qb), up to global phase.
+pub fn synthesize(u: &Matrix, qa: &str, qb: &str) -> String {
+    let names = [qa, qb];
+    let mut lines = Vec::new();
+    for step in circuit(u) {
+        match step {
+            Step::Local(gate, q) => local(&gate, names[q], &mut lines),
+            Step::Rz(turn, q) => lines.push(format!("rz({}) {}", turn, names[q])),
+            Step::Ry(turn, q) => lines.push(format!("ry({}) {}", turn, names[q])),
+            Step::Cnot(c, t) => lines.push(format!("cnot {} {}", names[c], names[t])),
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use linalg::{phase_between, rx, ry, rz};
+    use gatefmt::fmt_gates;
+    use qvm::QVM;
+    use std::collections::BTreeMap;
+
+    fn check(qvm: &QVM, expr: &str) {
+        let fragment = qvm.synthesize(expr, 3, 5).unwrap();
+        let cnots = fragment.iter().filter(|inst| inst.parts().0 == "cnot").count();
+        assert_eq!(cnots, 3);
+    }
+    #[test]
+    fn builtins() {
+        let mut qvm = QVM::new();
+        check(&qvm, "cnot");
+        check(&qvm, "swap");
+        check(&qvm, "i2");
+        qvm.merge_gates("s:\n1, 0\n0, i").unwrap();
+        check(&qvm, "ctrl @ s");
+        check(&qvm, "ctrl @ h");
+        check(&qvm, "dagger ctrl @ pow(3) @ s");
+        check(&qvm, "ctrl @ rx(pi/3)");
+    }
+    #[test]
+    fn generic() {
+        // a scrambled two qubit gate with every canonical coefficient non zero
+        let first = kron(&mul(&rx(0.3), &ry(1.2)), &mul(&rz(0.7), &rx(-2.1)));
+        let last = kron(&mul(&ry(-0.8), &rz(2.5)), &mul(&rx(1.4), &ry(0.2)));
+        let canonical = {
+            let mut d = vec![vec![C0; 4]; 4];
+            let m = magic();
+            let angles = [0.1, 0.9, -0.4, 2.2];
+            for k in 0..4 {
+                d[k][k] = Complex::from_polar(&1.0, &angles[k]);
+            }
+            mul(&mul(&m, &d), &adjoint(&m))
+        };
+        let u = mul(&mul(&last, &canonical), &first);
+        let k = kak(&u);
+        let rebuilt = {
+            let before = kron(&k.before.0, &k.before.1);
+            let after = kron(&k.after.0, &k.after.1);
+            let x = vec![vec![C0, C1], vec![C1, C0]];
+            let y = vec![vec![C0, -CI], vec![CI, C0]];
+            let z = vec![vec![C1, C0], vec![C0, -C1]];
+            let (xx, yy, zz) = (kron(&x, &x), kron(&y, &y), kron(&z, &z));
+            // the three terms commute and square to one
+            let exp = |p: &Matrix, t: Float| -> Matrix {
+                (0..4)
+                    .map(|i| {
+                        (0..4)
+                            .map(|j| {
+                                let id = if i == j { C1 } else { C0 };
+                                id * t.cos() + p[i][j] * CI * t.sin()
+                            })
+                            .collect()
+                    })
+                    .collect()
+            };
+            let middle = mul(&mul(&exp(&xx, k.a), &exp(&yy, k.b)), &exp(&zz, k.c));
+            mul(&mul(&after, &middle), &before)
+        };
+        assert!(phase_between(&u, &rebuilt, 1e-4).is_some());
+
+        let mut gates = BTreeMap::new();
+        gates.insert("u".to_string(), u);
+        let mut qvm = QVM::new();
+        qvm.merge_gates(&fmt_gates(&gates)).unwrap();
+        check(&qvm, "u");
+    }
+}
@@ -0,0 +1,298 @@
+use float::{Float, TOL, ZERO_TOL};
+use qvm::{Complex, Matrix, C0, C1};
+
+pub fn identity(dim: usize) -> Matrix {
+    let mut ret = vec![vec![C0; dim]; dim];
+    for (i, row) in ret.iter_mut().enumerate() {
+        row[i] = C1;
+    }
+    ret
+}
+
+pub fn adjoint(m: &Matrix) -> Matrix {
+    let dim = m.len();
+    (0..dim).map(|i| (0..dim).map(|j| m[j][i].conj()).collect()).collect()
+}
+
+fn off_diagonal(m: &Matrix) -> Float {
+    let mut sum = 0.0;
+    for (i, row) in m.iter().enumerate() {
+        for (j, item) in row.iter().enumerate() {
+            if i != j {
+                sum += item.norm_sqr();
+            }
+        }
+    }
+    sum
+}
+
+// Eigen decomposition of a hermitian matrix by cyclic jacobi rotations.
+// Returns the eigenvalues and a unitary whose columns are the eigenvectors.
+pub fn eigh(m: &Matrix) -> (Vec<Float>, Matrix) {
+    let dim = m.len();
+    let mut a = m.clone();
+    let mut v = identity(dim);
+    let scale: Float = m.iter().flat_map(|row| row.iter()).map(|c| c.norm_sqr()).sum();
+    for _ in 0..64 {
+        if off_diagonal(&a) <= ZERO_TOL * ZERO_TOL * scale {
+            break;
+        }
+        for p in 0..dim {
+            for q in p + 1..dim {
+                let g = a[p][q].norm();
+                if g == 0.0 {
+                    continue;
+                }
+                // phase away a[p][q], then rotate the real 2x2 block
+                let phase = Complex::from_polar(&1.0, &-a[p][q].arg());
+                let tau = (a[q][q].re - a[p][p].re) / (2.0 * g);
+                let t = tau.signum() / (tau.abs() + (1.0 + tau * tau).sqrt());
+                let c = 1.0 / (1.0 + t * t).sqrt();
+                let s = t * c;
+                let (jpp, jpq) = (Complex::new(c, 0.0), Complex::new(s, 0.0));
+                let (jqp, jqq) = (-phase * s, phase * c);
+                for row in a.iter_mut().chain(v.iter_mut()) {
+                    let (kp, kq) = (row[p], row[q]);
+                    row[p] = kp * jpp + kq * jqp;
+                    row[q] = kp * jpq + kq * jqq;
+                }
+                for k in 0..dim {
+                    let (pk, qk) = (a[p][k], a[q][k]);
+                    a[p][k] = jpp.conj() * pk + jqp.conj() * qk;
+                    a[q][k] = jpq.conj() * pk + jqq.conj() * qk;
+                }
+            }
+        }
+    }
+    ((0..dim).map(|i| a[i][i].re).collect(), v)
+}
+
+// Common eigenvectors of two commuting hermitian matrices: `a` is diagonalised
+// first, then each of its degenerate blocks by `b`.
+pub fn eig_commuting(a: &Matrix, b: &Matrix) -> Matrix {
+    let dim = a.len();
+    let (values, v) = eigh(a);
+    let mut order: Vec<usize> = (0..dim).collect();
+    order.sort_by(|&x, &y| values[x].partial_cmp(&values[y]).unwrap());
+    let mut columns: Vec<Vec<Complex>> = order.iter().map(|&j| (0..dim).map(|i| v[i][j]).collect()).collect();
+    let mut start = 0;
+    while start < dim {
+        let mut end = start + 1;
+        while end < dim && values[order[end]] - values[order[end - 1]] < TOL {
+            end += 1;
+        }
+        if end - start > 1 {
+            let block = &columns[start..end];
+            let sub: Matrix = block
+                .iter()
+                .map(|x| block.iter().map(|y| dot(x, &mul_vec(b, y))).collect())
+                .collect();
+            let (_, w) = eigh(&sub);
+            let rotated: Vec<Vec<Complex>> = (0..end - start)
+                .map(|k| {
+                    (0..dim)
+                        .map(|i| (0..end - start).map(|l| block[l][i] * w[l][k]).sum())
+                        .collect()
+                })
+                .collect();
+            for (k, col) in rotated.into_iter().enumerate() {
+                columns[start + k] = col;
+            }
+        }
+        start = end;
+    }
+    (0..dim).map(|i| columns.iter().map(|col| col[i]).collect()).collect()
+}
+
+// Eigen decomposition of a normal (eg. unitary) matrix, through its commuting
+// hermitian and anti-hermitian parts.
+pub fn eig_normal(m: &Matrix) -> (Vec<Complex>, Matrix) {
+    let dim = m.len();
+    let m_dag = adjoint(m);
+    let part = |sign: Float, scale: Complex| -> Matrix {
+        (0..dim)
+            .map(|i| (0..dim).map(|j| (m[i][j] + m_dag[i][j] * sign) * scale).collect())
+            .collect()
+    };
+    let v = eig_commuting(&part(1.0, Complex::new(0.5, 0.0)), &part(-1.0, Complex::new(0.0, -0.5)));
+    let eigenvalues = (0..dim)
+        .map(|j| {
+            let col: Vec<Complex> = v.iter().map(|row| row[j]).collect();
+            dot(&col, &mul_vec(m, &col))
+        })
+        .collect();
+    (eigenvalues, v)
+}
+
+pub fn det(m: &Matrix) -> Complex {
+    // gaussian elimination with partial pivoting
+    let dim = m.len();
+    let mut a = m.clone();
+    let mut ret = C1;
+    for col in 0..dim {
+        let pivot = (col..dim)
+            .max_by(|&x, &y| a[x][col].norm().partial_cmp(&a[y][col].norm()).unwrap())
+            .unwrap();
+        if a[pivot][col].norm() == 0.0 {
+            return C0;
+        }
+        if pivot != col {
+            a.swap(pivot, col);
+            ret = -ret;
+        }
+        ret *= a[col][col];
+        for row in col + 1..dim {
+            let factor = a[row][col] / a[col][col];
+            for k in col..dim {
+                let sub = factor * a[col][k];
+                a[row][k] -= sub;
+            }
+        }
+    }
+    ret
+}
+
+// the phase p with v = p u, if there is one
+pub fn phase_between(u: &Matrix, v: &Matrix, tol: Float) -> Option<Complex> {
+    let dim = u.len();
+    let mut pivot = (0, 0);
+    for i in 0..dim {
+        for j in 0..dim {
+            if u[i][j].norm() > u[pivot.0][pivot.1].norm() {
+                pivot = (i, j);
+            }
+        }
+    }
+    let phase = v[pivot.0][pivot.1] / u[pivot.0][pivot.1];
+    let same = (0..dim).all(|i| (0..dim).all(|j| (v[i][j] - phase * u[i][j]).norm() <= tol));
+    if same && (phase.norm() - 1.0).abs() <= tol {
+        Some(phase)
+    } else {
+        None
+    }
+}
+
+pub fn mul_vec(m: &Matrix, v: &[Complex]) -> Vec<Complex> {
+    m.iter()
+        .map(|row| row.iter().zip(v.iter()).map(|(a, b)| a * b).sum())
+        .collect()
+}
+
+// <a|b>
+pub fn dot(a: &[Complex], b: &[Complex]) -> Complex {
+    a.iter().zip(b.iter()).map(|(x, y)| x.conj() * y).sum()
+}
+
+// U = e^(i phase) rz(phi) ry(theta) rz(lambda)
+pub struct Euler {
+    pub phase: Float,
+    pub phi: Float,
+    pub theta: Float,
+    pub lambda: Float,
+}
+
+pub fn euler_zyz(u: &Matrix) -> Euler {
+    let det = u[0][0] * u[1][1] - u[0][1] * u[1][0];
+    let phase = det.arg() / 2.0;
+    let su: Vec<Vec<Complex>> = u
+        .iter()
+        .map(|row| row.iter().map(|c| c * Complex::from_polar(&1.0, &-phase)).collect())
+        .collect();
+    let theta = 2.0 * su[1][0].norm().atan2(su[0][0].norm());
+    // phi + lambda = 2 arg(u11) and phi - lambda = 2 arg(u10), either may be undefined
+    let sum = if su[1][1].norm() > ZERO_TOL { su[1][1].arg() } else { 0.0 };
+    let diff = if su[1][0].norm() > ZERO_TOL { su[1][0].arg() } else { 0.0 };
+    Euler {
+        phase,
+        phi: sum + diff,
+        theta,
+        lambda: sum - diff,
+    }
+}
+
+pub fn rz(angle: Float) -> Matrix {
+    vec![
+        vec![Complex::from_polar(&1.0, &(-angle / 2.0)), C0],
+        vec![C0, Complex::from_polar(&1.0, &(angle / 2.0))],
+    ]
+}
+
+pub fn ry(angle: Float) -> Matrix {
+    let (s, c) = (angle / 2.0).sin_cos();
+    vec![
+        vec![Complex::new(c, 0.0), Complex::new(-s, 0.0)],
+        vec![Complex::new(s, 0.0), Complex::new(c, 0.0)],
+    ]
+}
+
+pub fn rx(angle: Float) -> Matrix {
+    let (s, c) = (angle / 2.0).sin_cos();
+    vec![
+        vec![Complex::new(c, 0.0), Complex::new(0.0, -s)],
+        vec![Complex::new(0.0, -s), Complex::new(c, 0.0)],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qvm::{mul, tensor_product, CI};
+
+    #[test]
+    fn hermitian_eigenvectors() {
+        let m = vec![
+            vec![Complex::new(2.0, 0.0), Complex::new(1.0, -1.0), C0],
+            vec![Complex::new(1.0, 1.0), Complex::new(3.0, 0.0), CI],
+            vec![C0, -CI, Complex::new(-1.0, 0.0)],
+        ];
+        let (values, v) = eigh(&m);
+        let mv = mul(&m, &v);
+        for (j, value) in values.iter().enumerate() {
+            for i in 0..3 {
+                assert!((mv[i][j] - v[i][j] * value).norm() < 1e-5);
+            }
+        }
+        let vv = mul(&adjoint(&v), &v);
+        for i in 0..3 {
+            for j in 0..3 {
+                assert!((vv[i][j] - identity(3)[i][j]).norm() < 1e-5);
+            }
+        }
+        let trace: Float = values.iter().sum();
+        assert!((trace - 4.0).abs() < 1e-5);
+    }
+    #[test]
+    fn unitary_eigenvectors() {
+        // diag(1, 1, i, -1) in a scrambled basis, degenerate eigenvalue included
+        let h = Complex::new(1.0 / (2.0 as Float).sqrt(), 0.0);
+        let b = tensor_product(&mul(&rx(0.7), &ry(1.9)), &vec![vec![h, h], vec![h, -h]]);
+        let mut d = identity(4);
+        d[2][2] = CI;
+        d[3][3] = -C1;
+        let u = mul(&mul(&b, &d), &adjoint(&b));
+        let (values, v) = eig_normal(&u);
+        let uv = mul(&u, &v);
+        for (j, value) in values.iter().enumerate() {
+            assert!((value.norm() - 1.0).abs() < 1e-4);
+            for i in 0..4 {
+                assert!((uv[i][j] - v[i][j] * value).norm() < 1e-4);
+            }
+        }
+        let ones = values.iter().filter(|v| (*v - C1).norm() < 1e-4).count();
+        assert_eq!(ones, 2);
+    }
+    #[test]
+    fn euler_angles() {
+        let u = mul(&mul(&rz(0.3), &ry(1.1)), &rz(-2.0));
+        let u: Matrix = u.iter().map(|row| row.iter().map(|c| c * CI).collect()).collect();
+        let e = euler_zyz(&u);
+        let back = mul(&mul(&rz(e.phi), &ry(e.theta)), &rz(e.lambda));
+        let phase = Complex::from_polar(&1.0, &e.phase);
+        for i in 0..2 {
+            for j in 0..2 {
+                assert!((back[i][j] * phase - u[i][j]).norm() < 1e-5);
+            }
+        }
+        assert!((e.theta - 1.1).abs() < 1e-5);
+    }
+}
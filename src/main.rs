@@ -17,7 +17,23 @@ extern crate stdweb;
 extern crate serde_derive;
 
 
+mod cliffordt;
+mod condition;
+mod density;
+mod exact;
+mod float;
+mod gatefmt;
+mod inspect;
+mod kak;
+mod linalg;
+mod mps;
+mod optimize;
+mod pauli;
 mod qvm;
+mod simulator;
+mod sparse;
+mod stabilizer;
+mod transpile;
 
 use yew::html::{App, Html};
 use yew::{initialize, run_loop};
@@ -49,6 +65,10 @@ pub enum Msg {
     Prev,
     Next,
     End,
+    Continue,
+    ToggleBreakpoint(usize),
+    SetConditions,
+    SetWatches,
 
     Load(Example),
     EditProgram,
@@ -56,19 +76,84 @@ pub enum Msg {
 
     EditGates,
     SaveGates,
+    ReplaceGates,
+    RemoveGate,
+    RestoreGates,
+    ShowGatesJson,
+
+    SaveObservables,
+    SavePartition,
+
+    ShowUnitary,
+    HideUnitary,
+
+    CheckEquivalence,
+    AppendInverse,
+    Transpile,
+    Optimize,
+
+    Inspect,
+    AppendSynthesis,
+    Approximate,
+
+    SetBackend,
+    LookupAmplitudes,
+    SaveSnapshot,
+    RestoreSnapshot,
+    SetDriftTolerance,
+    ToggleRenormalise,
+    ToggleExact,
 }
 
 struct Context {}
 struct Editor {
     state: State,
     edit: String,
-    error: bool,
+    error: Option<String>,
 }
 
 pub struct Model {
     qvm: qvm::QVM,
     gates: Editor,
     program: Editor,
+    observables: String,
+    // each line of `observables` parsed once when saved, to evaluate on every render
+    parsed_observables: Vec<(String, Result<pauli::Observable, String>)>,
+    partition: String,
+    range: String,
+    // why the last Append Inverse did not append anything
+    inverse_error: Option<String>,
+    show_unitary: bool,
+    compare: String,
+    equivalence: Option<Result<qvm::Equivalence, String>>,
+    basis: String,
+    // notes or the error from the last transpile or optimise
+    rewrite: Option<Result<Vec<String>, String>>,
+    inspect: String,
+    synthesis: String,
+    // the inspected gate and its synthesis on `synthesis`, worked out when
+    // either or the gates change rather than on every render
+    inspection: Result<inspect::Inspection, String>,
+    synthesized: Result<Vec<qvm::Instruction>, String>,
+    accuracy: String,
+    approximation: Option<Result<cliffordt::Approximation, String>>,
+    // eg. "auto" or "mps 16", see simulator::parse_backend
+    backend: String,
+    backend_error: Option<String>,
+    saved: Option<qvm::Snapshot>,
+    // basis states to read off a wide mps, one per line
+    lookup: String,
+    // how far the norm may drift before it is reported
+    drift_tol: String,
+    drift_error: Option<String>,
+    // amplitudes and equivalence in Z[1/√2, i] for clifford+t programs
+    exact: bool,
+    // predicates to break on, one per line, see condition::Condition
+    conditions: String,
+    conditions_error: Option<String>,
+    // quantities to show after every step, one per line, see condition::Quantity
+    watches: String,
+    watches_error: Option<String>,
 }
 
 fn main() {
@@ -79,16 +164,46 @@ fn main() {
         gates: Editor {
             state: State::Ready,
             edit: "".to_string(),
-            error: false,
+            error: None,
         },
         program: Editor {
             state: State::Ready,
             edit: "".to_string(),
-            error: false,
+            error: None,
         },
+        observables: "Z0 Z1\nX0 X1\n0.5 ZZ - 0.5 YY".to_string(),
+        parsed_observables: vec![],
+        partition: "0".to_string(),
+        range: "".to_string(),
+        inverse_error: None,
+        show_unitary: false,
+        compare: "".to_string(),
+        equivalence: None,
+        basis: "cnot rz sx x".to_string(),
+        rewrite: None,
+        inspect: "h".to_string(),
+        synthesis: "0 1".to_string(),
+        inspection: Err("".to_string()),
+        synthesized: Ok(vec![]),
+        accuracy: "0.01".to_string(),
+        approximation: None,
+        backend: "auto".to_string(),
+        backend_error: None,
+        saved: None,
+        lookup: "".to_string(),
+        drift_tol: format!("{:e}", qvm::TOL),
+        drift_error: None,
+        exact: false,
+        conditions: "".to_string(),
+        conditions_error: None,
+        watches: "P(0)\nE(Z0 Z1)".to_string(),
+        watches_error: None,
     };
     model.qvm.update(&model.program.edit);
     model.gates.edit = model.qvm.show_gates();
+    reinspect(&mut model);
+    parse_observables(&mut model);
+    model.qvm.set_watches(&model.watches).unwrap();
     let mut ctx = Context {};
     update(&mut ctx, &mut model, Msg::Load(Example::Bell));
     app.mount(ctx, model, update, view);
@@ -107,6 +222,25 @@ fn reset_prog(model: &mut Model, prog: String) {
     model.qvm.update(&prog);
     model.program.edit = prog;
     model.qvm.reset();
+    model.equivalence = None;
+    model.rewrite = None;
+}
+// Loads `prog` if it runs, and otherwise leaves it in the editor with the
+// reason it does not.
+fn save_program(model: &mut Model, prog: String) {
+    let editor = &mut model.program;
+    editor.state = if model.qvm.update(&prog) {
+        editor.edit = prog;
+        editor.error = None;
+        State::Ready
+    } else {
+        editor.error = Some(match qvm::parse_program(&prog) {
+            Some(ref parsed) => model.qvm.check(parsed).err().unwrap_or_default(),
+            None => "malformed program".into(),
+        });
+        editor.edit = prog;
+        State::Editing
+    };
 }
 fn update(_: &mut Context, model: &mut Model, msg: Msg) {
     match msg {
@@ -150,34 +284,173 @@ h 7".to_string(),
         Msg::EditGates => {
             model.gates.state = State::Editing;
         }
-        Msg::SaveGates => {
+        Msg::SaveGates | Msg::ReplaceGates => {
             let gates = get_text("gates");
+            let result = match msg {
+                Msg::SaveGates => model.qvm.merge_gates(&gates),
+                _ => model.qvm.set_gates(&gates),
+            };
+            reinspect(model);
             let editor = &mut model.gates;
-            editor.state = if model.qvm.set_gates(&gates) {
-                editor.edit = gates;
-                editor.error = false;
-                State::Ready
-            } else {
-                editor.edit = gates;
-                editor.error = true;
-                State::Editing
+            editor.state = match result {
+                Ok(()) => {
+                    editor.edit = model.qvm.show_gates();
+                    editor.error = None;
+                    State::Ready
+                }
+                Err(e) => {
+                    editor.edit = gates;
+                    editor.error = Some(e);
+                    State::Editing
+                }
             };
         }
+        Msg::RemoveGate => {
+            let name = get_text("gate_name");
+            model.gates.error = model.qvm.remove_gate(name.trim()).err();
+            model.gates.edit = model.qvm.show_gates();
+            reinspect(model);
+        }
+        Msg::ShowGatesJson => {
+            model.gates.edit = model.qvm.show_gates_json();
+        }
+        Msg::RestoreGates => {
+            model.gates.error = model.qvm.restore_gates().err();
+            model.gates.edit = model.qvm.show_gates();
+            reinspect(model);
+        }
+        Msg::SaveObservables => {
+            model.observables = get_text("observables");
+            parse_observables(model);
+        }
+        Msg::SavePartition => {
+            model.partition = get_text("partition");
+        }
+        Msg::ShowUnitary => {
+            model.range = get_text("range");
+            model.show_unitary = true;
+        }
+        Msg::HideUnitary => {
+            model.show_unitary = false;
+        }
+        Msg::CheckEquivalence => {
+            model.compare = get_text("compare");
+            model.equivalence = Some(match qvm::parse_program(&model.compare) {
+                Some(ref other) if model.exact => model.qvm.compare_exact(other),
+                Some(ref other) => model.qvm.compare(other),
+                None => Err("malformed program".into()),
+            });
+        }
+        Msg::Transpile => {
+            // the lowered program goes in the compare box, checked against this one
+            model.basis = get_text("basis");
+            model.rewrite = Some(match model.qvm.transpile(&model.basis) {
+                Ok(lowered) => {
+                    model.compare = qvm::fmt_program(&lowered);
+                    model.equivalence = if model.qvm.is_unitary(&lowered) {
+                        Some(model.qvm.compare(&lowered))
+                    } else {
+                        None
+                    };
+                    Ok(vec![format!("{} instructions lowered to {}", model.qvm.program.len(), lowered.len())])
+                }
+                Err(e) => Err(e),
+            });
+        }
+        Msg::Optimize => {
+            model.rewrite = Some(match model.qvm.optimize() {
+                Ok((optimized, removed)) => {
+                    model.compare = qvm::fmt_program(&optimized);
+                    model.equivalence = Some(model.qvm.compare(&optimized));
+                    Ok(removed)
+                }
+                Err(e) => Err(e),
+            });
+        }
+        Msg::AppendInverse => {
+            model.range = get_text("range");
+            let inverse = match line_range(&model.range, model.qvm.program.len()) {
+                Some((start, end)) => model.qvm.inverse_range(start, end),
+                None => Err("bad range".into()),
+            };
+            match inverse {
+                Ok(inverse) => {
+                    let prog = model.qvm.read_program() + &qvm::fmt_program(&inverse);
+                    model.inverse_error = None;
+                    save_program(model, prog);
+                }
+                Err(e) => model.inverse_error = Some(e),
+            }
+        }
+        Msg::Inspect => {
+            model.inspect = get_text("inspect");
+            model.synthesis = get_text("synthesis");
+            reinspect(model);
+            model.approximation = None;
+        }
+        Msg::Approximate => {
+            model.inspect = get_text("inspect");
+            reinspect(model);
+            model.accuracy = get_text("accuracy");
+            model.approximation = Some(match model.accuracy.trim().parse::<float::Float>() {
+                Ok(accuracy) => model.qvm.approximate(model.inspect.trim(), accuracy),
+                Err(_) => Err(format!("bad accuracy '{}'", model.accuracy.trim())),
+            });
+        }
+        Msg::AppendSynthesis => {
+            model.inspect = get_text("inspect");
+            model.synthesis = get_text("synthesis");
+            reinspect(model);
+            let prog = match model.synthesized {
+                Ok(ref fragment) => Some(model.qvm.read_program() + &qvm::fmt_program(fragment)),
+                Err(_) => None,
+            };
+            if let Some(prog) = prog {
+                save_program(model, prog);
+            }
+        }
+        Msg::SetBackend => {
+            model.backend = get_text("backend");
+            model.backend_error = simulator::parse_backend(&model.backend)
+                .and_then(|backend| model.qvm.select_backend(backend))
+                .err();
+            model.saved = None;
+        }
+        Msg::SaveSnapshot => {
+            model.saved = Some(model.qvm.snapshot());
+        }
+        Msg::RestoreSnapshot => {
+            let restored = match model.saved {
+                Some(ref saved) => model.qvm.restore(saved).is_ok(),
+                None => false,
+            };
+            if !restored {
+                model.saved = None;
+            }
+        }
+        Msg::SetDriftTolerance => {
+            model.drift_tol = get_text("drift_tol");
+            model.drift_error = match model.drift_tol.trim().parse::<float::Float>() {
+                Ok(tol) => model.qvm.set_drift_tolerance(tol).err(),
+                Err(_) => Some(format!("bad tolerance '{}'", model.drift_tol.trim())),
+            };
+        }
+        Msg::ToggleRenormalise => {
+            let renormalise = !model.qvm.renormalising();
+            model.qvm.set_renormalise(renormalise);
+        }
+        Msg::ToggleExact => {
+            model.exact = !model.exact;
+        }
+        Msg::LookupAmplitudes => {
+            model.lookup = get_text("lookup");
+        }
         Msg::EditProgram => {
             model.program.state = State::Editing;
         }
         Msg::SaveProgram => {
             let prog = get_text("program");
-            let editor = &mut model.program;
-            editor.state = if model.qvm.update(&prog) {
-                editor.edit = prog;
-                editor.error = false;
-                State::Ready
-            } else {
-                editor.edit = prog;
-                editor.error = true;
-                State::Editing
-            };
+            save_program(model, prog);
         }
         Msg::Beginning => loop {
             if model.qvm.counter == 0 {
@@ -197,11 +470,656 @@ h 7".to_string(),
             }
             model.qvm.next();
         },
+        Msg::Continue => {
+            model.qvm.resume();
+        }
+        Msg::SetConditions => {
+            model.conditions = get_text("conditions");
+            model.conditions_error = model.qvm.set_conditions(&model.conditions).err();
+        }
+        Msg::SetWatches => {
+            model.watches = get_text("watches");
+            model.watches_error = model.qvm.set_watches(&model.watches).err();
+        }
+        Msg::ToggleBreakpoint(line) => {
+            // the blank line after the last instruction has nothing to break on
+            model.qvm.toggle_breakpoint(line).ok();
+        }
+    }
+}
+
+fn bloch_spheres(model: &Model) -> Html<Msg> {
+    // x-z plane projection, the dot grows as y points towards the viewer
+    let sphere = |(qb, b): (usize, &density::Bloch)| {
+        let size = 8.0 + 4.0 * b.y;
+        let dot = format!(
+            "left: {}%; top: {}%; width: {}px; height: {}px;",
+            50.0 + 50.0 * b.x,
+            50.0 - 50.0 * b.z,
+            size,
+            size
+        );
+        html! {
+            <div class="level-item",>
+                <div>
+                    <div class="bloch",>
+                        <div class="bloch-equator",></div>
+                        <div class="bloch-dot", style=dot,></div>
+                    </div>
+                    <div class="has-text-centered",>{ format!("q{}", qb) }</div>
+                    <div class="is-size-7",>{ format!("x {:+.3}", b.x) }</div>
+                    <div class="is-size-7",>{ format!("y {:+.3}", b.y) }</div>
+                    <div class="is-size-7",>{ format!("z {:+.3}", b.z) }</div>
+                    <div class="is-size-7",>{ format!("p {:.3}", b.purity) }</div>
+                </div>
+            </div>
+        }
+    };
+    let vectors = match model.qvm.bloch_vectors() {
+        Ok(vectors) => html! {
+            <div class="level",>
+                { for vectors.iter().enumerate().rev().map(sphere) }
+            </div>
+        },
+        Err(e) => html! { <div>{ format!("ERROR! {}", e) }</div> },
+    };
+    html! {
+        <div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{"Bloch Vectors: "}</div>
+                </div>
+            </div>
+            { vectors }
+        </div>
+    }
+}
+
+fn entanglement(model: &Model) -> Html<Msg> {
+    let part: Result<Vec<usize>, _> = model
+        .partition
+        .split_whitespace()
+        .map(|qb| qb.parse::<usize>())
+        .collect();
+    let entropy = match part {
+        Ok(ref part) => match model.qvm.entanglement_entropy(part) {
+            Ok(entropy) => format!("{:.4}", entropy),
+            Err(e) => format!("ERROR! {}", e),
+        },
+        _ => "ERROR!".to_string(),
+    };
+    let links = match model.qvm.entanglement_links() {
+        Ok(links) => links,
+        Err(e) => return html! { <div>{ format!("Entanglement: ERROR! {}", e) }</div> },
+    };
+    // adjacency matrix of mutual information, shaded by strength (max 2 bits)
+    let cell = |a: usize, b: usize| {
+        if a == b {
+            return html! { <td>{ format!("q{}", a) }</td> };
+        }
+        let link = links
+            .iter()
+            .find(|l| l.a == a.min(b) && l.b == a.max(b))
+            .unwrap();
+        let shade = format!(
+            "background: rgba(50, 115, 220, {:.2});",
+            link.mutual_information / 2.0
+        );
+        html! {
+            <td style=shade,>{ format!("{:.2}", link.mutual_information) }</td>
+        }
+    };
+    let row = |a: usize| {
+        html! {
+            <tr>{ for (0..qvm::NQ).rev().map(|b| cell(a, b)) }</tr>
+        }
+    };
+    let edge = |link: &density::Link| {
+        html! {
+            <div class="level",>
+                <div class="level-item",>
+                    <div class=("tags","has-addons"),>
+                        <div class=("tag","is-info"),>
+                             { format!("q{} - q{}", link.a, link.b) }
+                        </div>
+                        <div class="tag",>
+                             { format!("I {:.3}  C {:.3}", link.mutual_information, link.concurrence) }
+                        </div>
+                    </div>
+                </div>
+            </div>
+        }
+    };
+    html! {
+        <div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{"Entanglement: "}</div>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    <textarea id="partition", cols=20, rows=1,>{&model.partition} </textarea>
+                    <button class="button", onclick=move|_| Msg::SavePartition,>{"Split"}</button>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{ format!("S({}) = {}", model.partition.trim(), entropy) }</div>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    <table class=("table","is-bordered","is-narrow"),>
+                        { for (0..qvm::NQ).rev().map(row) }
+                    </table>
+                </div>
+            </div>
+            { for links.iter().filter(|l| l.mutual_information > qvm::TOL).map(edge) }
+        </div>
+    }
+}
+
+fn line_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    // 1-based inclusive line numbers, matching the program listing
+    let bounds: Vec<usize> = range
+        .split_whitespace()
+        .filter_map(|n| n.parse().ok())
+        .filter(|&n| n > 0)
+        .collect();
+    let (start, end) = match bounds.len() {
+        0 => (0, len),
+        1 => (bounds[0] - 1, bounds[0]),
+        _ => (bounds[0] - 1, bounds[1]),
+    };
+    if start >= end || end > len {
+        None
+    } else {
+        Some((start, end))
+    }
+}
+
+fn unitary(model: &Model) -> Html<Msg> {
+    let range = line_range(&model.range, model.qvm.program.len());
+    let heatmap = if !model.show_unitary {
+        html! { <div></div> }
+    } else if let Some((start, end)) = range {
+        let qubits = model.qvm.active_qubits(start, end);
+        let unitary = model.qvm.unitary_range(start, end);
+        if let Err(ref e) = unitary {
+            html! { <div>{ format!("ERROR! {}", e) }</div> }
+        } else if qubits.len() > 5 {
+            html! { <div>{ format!("{} active qubits, too large to draw", qubits.len()) }</div> }
+        } else {
+            // qubits[0] is the most significant bit, as in the ket list
+            let qubits: Vec<usize> = qubits.into_iter().rev().collect();
+            let block = qvm::restrict(unitary.as_ref().unwrap(), &qubits);
+            // hue is the phase, saturation the magnitude
+            let cell = |c: &qvm::Complex| {
+                let hue = (c.arg().to_degrees() + 360.0) % 360.0;
+                let shade = format!(
+                    "width: 14px; height: 14px; padding: 0; background: hsl({:.0}, 80%, {:.0}%);",
+                    hue,
+                    100.0 - 50.0 * c.norm()
+                );
+                html! { <td style=shade,></td> }
+            };
+            let row = |r: &Vec<qvm::Complex>| {
+                html! { <tr>{ for r.iter().map(cell) }</tr> }
+            };
+            let label = qubits.iter().map(|qb| format!("q{}", qb)).collect::<Vec<_>>().join(" ");
+            html! {
+                <div>
+                    <div class="has-text-centered",>{ label }</div>
+                    <table class="table",>
+                        { for block.iter().map(row) }
+                    </table>
+                </div>
+            }
+        }
+    } else {
+        html! { <div>{"ERROR! bad range"}</div> }
+    };
+    html! {
+        <div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{"Lines: "}</div>
+                    <textarea id="range", cols=10, rows=1,>{&model.range} </textarea>
+                    <button class="button", onclick=move|_| Msg::ShowUnitary,>{"Show Unitary"}</button>
+                    <button class="button", onclick=move|_| Msg::HideUnitary,>{"Hide"}</button>
+                    <button class="button", onclick=move|_| Msg::AppendInverse,>{"Append Inverse"}</button>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{ model.inverse_error.as_ref().map(|e| format!("ERROR! {}", e)).unwrap_or_default() }</div>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    { heatmap }
+                </div>
+            </div>
+        </div>
+    }
+}
+
+fn equivalence(model: &Model) -> Html<Msg> {
+    let amplitude = |&(n, ours, theirs): &(usize, qvm::Complex, qvm::Complex)| {
+        html! {
+            <div class="level",>
+                <div class="level-item",>
+                    <div class=("tags","has-addons"),>
+                        <div class=("tag","is-info"),>
+                             { format!("|{:08b}>", n) }
+                        </div>
+                        <div class="tag",>
+                             { format!("{}", ours) }
+                        </div>
+                        <div class=("tag","is-warning"),>
+                             { format!("{}", theirs) }
+                        </div>
+                    </div>
+                </div>
+            </div>
+        }
+    };
+    let note = |text: String| {
+        html! {
+            <div class="level",>
+                <div class="level-item",>
+                    <div class="is-size-7",>{ text }</div>
+                </div>
+            </div>
+        }
+    };
+    let notes = match model.rewrite {
+        Some(Ok(ref notes)) => notes.clone(),
+        Some(Err(ref e)) => vec![format!("ERROR! {}", e)],
+        None => Vec::new(),
+    };
+    let result = match model.equivalence {
+        None => html! { <div></div> },
+        Some(Err(ref e)) => html! { <div>{ format!("ERROR! {}", e) }</div> },
+        Some(Ok(qvm::Equivalence::Same { phase })) => html! {
+            <div class="has-text-success",>{ format!("Equivalent, up to global phase {}", phase) }</div>
+        },
+        Some(Ok(qvm::Equivalence::Differs { input, ref amplitudes })) => html! {
+            <div>
+                <div class="has-text-danger",>
+                    { format!("Not equivalent: input |{:08b}> gives (program, other)", input) }
+                </div>
+                { for amplitudes.iter().map(amplitude) }
+            </div>
+        },
+    };
+    html! {
+        <div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{"Compare With: "}</div>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    <textarea id="compare", cols=30, rows=6,>{&model.compare} </textarea>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    <button class="button", onclick=move|_| Msg::CheckEquivalence,>{"Check Equivalence"}</button>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{"Basis: "}</div>
+                    <textarea id="basis", cols=20, rows=1,>{&model.basis} </textarea>
+                    <button class="button", onclick=move|_| Msg::Transpile,>{"Transpile"}</button>
+                    <button class="button", onclick=move|_| Msg::Optimize,>{"Optimise"}</button>
+                </div>
+            </div>
+            { for notes.into_iter().map(note) }
+            <div class="level",>
+                <div class="level-item",>
+                    { result }
+                </div>
+            </div>
+        </div>
+    }
+}
+
+fn reinspect(model: &mut Model) {
+    model.inspection = model.qvm.inspect(model.inspect.trim());
+    model.synthesized = synthesize(model);
+}
+fn synthesize(model: &Model) -> Result<Vec<qvm::Instruction>, String> {
+    let qubits: Vec<usize> = model
+        .synthesis
+        .split_whitespace()
+        .map(|qb| qb.parse::<usize>().map_err(|_| format!("bad qubit '{}'", qb)))
+        .collect::<Result<_, _>>()?;
+    if qubits.len() != 2 {
+        return Err("synthesis needs two qubits".into());
+    }
+    model.qvm.synthesize(model.inspect.trim(), qubits[0], qubits[1])
+}
+
+fn inspector(model: &Model) -> Html<Msg> {
+    let fmt_row = |row: &Vec<qvm::Complex>| {
+        row.iter().map(|c| gatefmt::fmt_complex(*c)).collect::<Vec<_>>().join(", ")
+    };
+    let line = |text: String| {
+        html! {
+            <div class="level",>
+                <div class="level-item",>
+                    <div class="is-size-7",>{ text }</div>
+                </div>
+            </div>
+        }
+    };
+    let mut lines = Vec::new();
+    match model.inspection {
+        Err(ref e) => lines.push(format!("ERROR! {}", e)),
+        Ok(ref found) => {
+            lines.push("matrix:".to_string());
+            lines.extend(found.matrix.iter().map(fmt_row));
+            lines.push("eigenvalues, eigenvectors:".to_string());
+            for (value, vector) in &found.eigen {
+                lines.push(format!(
+                    "e^({:.4} pi i)  [{}]",
+                    value.arg() / float::consts::PI,
+                    fmt_row(vector)
+                ));
+            }
+            lines.push("pauli terms:".to_string());
+            for (coeff, string) in &found.paulis {
+                lines.push(format!("({}) {}", gatefmt::fmt_complex(*coeff), string));
+            }
+            if let Some(ref e) = found.euler {
+                lines.push(format!(
+                    "e^({:.4} i) rz({:.4}) ry({:.4}) rz({:.4})",
+                    e.phase, e.phi, e.theta, e.lambda
+                ));
+            }
+            if found.matrix.len() == 4 {
+                lines.push(format!("synthesis on {}:", model.synthesis.trim()));
+                match model.synthesized {
+                    Ok(ref fragment) => lines.extend(qvm::fmt_program(fragment).lines().map(str::to_string)),
+                    Err(ref e) => lines.push(format!("ERROR! {}", e)),
+                }
+            }
+        }
+    }
+    match model.approximation {
+        Some(Ok(ref found)) => {
+            lines.push(format!(
+                "clifford+t: error {:.2e}, t-count {}, {} gates",
+                found.error,
+                found.t_count,
+                found.gates.len()
+            ));
+            lines.push(found.gates.join(" "));
+        }
+        Some(Err(ref e)) => lines.push(format!("ERROR! {}", e)),
+        None => {}
+    }
+    html! {
+        <div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{"Inspect Gate: "}</div>
+                    <textarea id="inspect", cols=20, rows=1,>{&model.inspect} </textarea>
+                    <button class="button", onclick=move|_| Msg::Inspect,>{"Inspect"}</button>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{"Synthesize On: "}</div>
+                    <textarea id="synthesis", cols=20, rows=1,>{&model.synthesis} </textarea>
+                    <button class="button", onclick=move|_| Msg::AppendSynthesis,>{"Append Synthesis"}</button>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{"Clifford+T Accuracy: "}</div>
+                    <textarea id="accuracy", cols=20, rows=1,>{&model.accuracy} </textarea>
+                    <button class="button", onclick=move|_| Msg::Approximate,>{"Approximate"}</button>
+                </div>
+            </div>
+            { for lines.into_iter().map(line) }
+        </div>
+    }
+}
+
+fn backend(model: &Model) -> Html<Msg> {
+    let simulator = model.qvm.backend();
+    let status = match model.backend_error {
+        Some(ref e) => format!("ERROR! {}", e),
+        None => {
+            let lost = 1.0 - model.qvm.truncation.iter().fold(1.0, |kept, t| kept * (1.0 - t));
+            let mut parts = vec![format!("{} on {} qubits", simulator.name(), simulator.width())];
+            parts.push(simulator.status());
+            if lost > 0.0 {
+                parts.push(format!("{:.2e} of the weight truncated", lost));
+            }
+            parts.retain(|part| !part.is_empty());
+            parts.join(", ")
+        }
+    };
+    // a wide state may have too many amplitudes to list, so they are read on demand
+    let amplitude = |line: &str| {
+        let value = match usize::from_str_radix(line.trim(), 2) {
+            Ok(n) if line.trim().len() == simulator.width() => match simulator.amplitude(n) {
+                Some(c) => format!("{}", c),
+                None => "ERROR! not available on this backend".to_string(),
+            },
+            _ => "ERROR!".to_string(),
+        };
+        html! {
+            <div class="level",>
+                <div class="level-item",>
+                    <div class=("tags","has-addons"),>
+                        <div class=("tag","is-info"),>
+                             { format!("|{}>", line.trim()) }
+                        </div>
+                        <div class="tag",>
+                             { value }
+                        </div>
+                    </div>
+                </div>
+            </div>
+        }
+    };
+    let lookup = if model.qvm.is_wide() {
+        html! {
+            <div>
+                <div class="level",>
+                    <div class="level-item",>
+                        <div>{"Amplitudes Of: "}</div>
+                        <textarea id="lookup", cols=30, rows=3,>{&model.lookup} </textarea>
+                        <button class="button", onclick=move|_| Msg::LookupAmplitudes,>{"Look Up"}</button>
+                    </div>
+                </div>
+                { for model.lookup.lines().filter(|line| !line.trim().is_empty()).map(amplitude) }
+            </div>
+        }
+    } else {
+        html! { <div></div> }
+    };
+    // a gate that is not quite unitary leaves probabilities that no longer sum to 1
+    let drift = match (model.drift_error.as_ref(), model.qvm.worst_drift()) {
+        (Some(e), _) => format!("ERROR! {}", e),
+        (None, Some((i, drift))) => format!(
+            "WARNING! the norm is {:.6}, line {} ({}) moved it the most, by {:+.2e}",
+            model.qvm.norm(),
+            i + 1,
+            qvm::fmt_program(&model.qvm.program[i..i + 1]).trim(),
+            drift
+        ),
+        (None, None) => format!("norm {:.6}", model.qvm.norm()),
+    };
+    let renormalise = if model.qvm.renormalising() { "Renormalising" } else { "Renormalise" };
+    html! {
+        <div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{"Backend: "}</div>
+                    <textarea id="backend", cols=10, rows=1,>{&model.backend} </textarea>
+                    <button class="button", onclick=move|_| Msg::SetBackend,>{"Set"}</button>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{ status }</div>
+                </div>
+            </div>
+            { lookup }
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{"Norm Tolerance: "}</div>
+                    <textarea id="drift_tol", cols=10, rows=1,>{&model.drift_tol} </textarea>
+                    <button class="button", onclick=move|_| Msg::SetDriftTolerance,>{"Set"}</button>
+                    <button class="button", onclick=move|_| Msg::ToggleRenormalise,>{ renormalise }</button>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{ drift }</div>
+                </div>
+            </div>
+        </div>
+    }
+}
+
+fn watches(model: &Model) -> Html<Msg> {
+    let watch = |(watch, value): (&(String, condition::Quantity), &Result<qvm::Complex, String>)| {
+        let (ref text, ref quantity) = *watch;
+        let value = match *value {
+            Ok(value) => quantity.fmt_value(value),
+            Err(ref e) => format!("ERROR! {}", e),
+        };
+        html! {
+            <div class="level",>
+                <div class="level-item",>
+                    <div class=("tags","has-addons"),>
+                        <div class=("tag","is-info"),>
+                             { text.clone() }
+                        </div>
+                        <div class="tag",>
+                             { value }
+                        </div>
+                    </div>
+                </div>
+            </div>
+        }
+    };
+    let error = match model.watches_error {
+        Some(ref e) => format!("ERROR! {}", e),
+        None => "".to_string(),
+    };
+    html! {
+        <div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{"Watch: "}</div>
+                    <textarea id="watches", cols=30, rows=3,>{&model.watches} </textarea>
+                    <button class="button", onclick=move|_| Msg::SetWatches,>{"Set"}</button>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{ error }</div>
+                </div>
+            </div>
+            { for model.qvm.watches().iter().zip(model.qvm.watched.iter()).map(watch) }
+        </div>
+    }
+}
+
+fn stabilizers(model: &Model) -> Html<Msg> {
+    // the generators of the state on the tableau, in ket order
+    let generator = |gen: String| {
+        html! {
+            <div class="level",>
+                <div class="level-item",>
+                    <div class="tag",>{ gen }</div>
+                </div>
+            </div>
+        }
+    };
+    let gens = match model.qvm.backend().generators() {
+        Some(gens) => gens,
+        None => return html! { <div></div> },
+    };
+    html! {
+        <div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{ format!("Stabilizer Generators ({} qubits): ", gens.len()) }</div>
+                </div>
+            </div>
+            { for gens.into_iter().map(generator) }
+        </div>
+    }
+}
+
+fn parse_observables(model: &mut Model) {
+    model.parsed_observables = model
+        .observables
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| (line.trim().to_string(), pauli::Observable::parse(line)))
+        .collect();
+}
+fn observables(model: &Model) -> Html<Msg> {
+    let observable = |&(ref line, ref parsed): &(String, Result<pauli::Observable, String>)| {
+        let value = match parsed.as_ref().map_err(|e| e.clone()).and_then(|o| model.qvm.expectation_of(o)) {
+            Ok(value) => format!("{:.6}", value),
+            Err(e) => format!("ERROR! {}", e),
+        };
+        html! {
+            <div class="level",>
+                <div class="level-item",>
+                    <div class=("tags","has-addons"),>
+                        <div class=("tag","is-info"),>
+                             { format!("<{}>", line) }
+                        </div>
+                        <div class="tag",>
+                             { value }
+                        </div>
+                    </div>
+                </div>
+            </div>
+        }
+    };
+    html! {
+        <div>
+            <div class="level",>
+                <div class="level-item",>
+                    <div>{"Observables: "}</div>
+                </div>
+            </div>
+            { for model.parsed_observables.iter().map(observable) }
+            <div class="level",>
+                <div class="level-item",>
+                    <textarea id="observables", cols=30, rows=4,>{&model.observables} </textarea>
+                </div>
+            </div>
+            <div class="level",>
+                <div class="level-item",>
+                    <button class="button", onclick=move|_| Msg::SaveObservables,>{"Evaluate"}</button>
+                </div>
+            </div>
+        </div>
     }
 }
 
 fn view(model: &Model) -> Html<Msg> {
-    let err = |editor: &Editor| if editor.error { "ERROR!" } else { "" };
+    let err = |editor: &Editor| match editor.error {
+        Some(ref e) => format!("ERROR! {}", e),
+        None => "".to_string(),
+    };
     let gates = match model.gates.state {
         State::Ready => html! {
             <div class="level",>
@@ -215,6 +1133,15 @@ fn view(model: &Model) -> Html<Msg> {
                 <div class="level",>
                     <div class="level-item",>
                         <button class="button", onclick=move|_| Msg::SaveGates,>{"Save Gates"}</button>
+                        <button class="button", onclick=move|_| Msg::ReplaceGates,>{"Replace Custom Gates"}</button>
+                        <button class="button", onclick=move|_| Msg::RestoreGates,>{"Restore Defaults"}</button>
+                        <button class="button", onclick=move|_| Msg::ShowGatesJson,>{"JSON"}</button>
+                    </div>
+                </div>
+                <div class="level",>
+                    <div class="level-item",>
+                        <textarea id="gate_name", cols=10, rows=1,></textarea>
+                        <button class="button", onclick=move|_| Msg::RemoveGate,>{"Remove Gate"}</button>
                     </div>
                 </div>
                 <div class="level",>
@@ -231,13 +1158,33 @@ fn view(model: &Model) -> Html<Msg> {
         },
     };
     let instruction = |(i, line): (usize, &str)| {
+        let line = match model.qvm.outcomes.get(i) {
+            Some(&Some(outcome)) if i < model.qvm.counter => format!("{}   => {}", line, outcome as u8),
+            _ => match (model.qvm.truncation.get(i), model.qvm.drift.get(i)) {
+                (Some(&lost), _) if i < model.qvm.counter && lost > 0.0 => format!("{}   (truncated {:.2e})", line, lost),
+                (_, Some(&drift)) if i < model.qvm.counter && drift.abs() > model.qvm.drift_tolerance() => {
+                    format!("{}   (norm {:+.2e})", line, drift)
+                }
+                _ => line.to_string(),
+            },
+        };
+        // the line number toggles a breakpoint before the line
+        let number = if model.qvm.breakpoints().contains(&i) {
+            html! {
+                <span class=("tag","is-danger"), onclick=move|_| Msg::ToggleBreakpoint(i),>{ (i + 1).to_string() }</span>
+            }
+        } else {
+            html! {
+                <span class="tag", onclick=move|_| Msg::ToggleBreakpoint(i),>{ (i + 1).to_string() }</span>
+            }
+        };
         if i == model.qvm.counter {
             html! {
-                <li><b>{ line }</b> <i class="has-text-info",>{"   (next instruction)"}</i></li>
+                <li>{ number } <b>{ line }</b> <i class="has-text-info",>{"   (next instruction)"}</i></li>
             }
         } else {
             html! {
-                <li>{ line }</li>
+                <li>{ number } { line }</li>
             }
         }
     };
@@ -266,6 +1213,96 @@ fn view(model: &Model) -> Html<Msg> {
         }
     };
 
+    // closed forms such as (1+i)/2√2 in place of rounded floats
+    let exact_ket = |n: usize, value: &exact::Exact| {
+        html! {
+            <div class="level",>
+                <div class="level-item",>
+                    <div class=("tags","has-addons"),>
+                        <div class=("tag","is-info"),>
+                             { format!("|{:08b}>", n) }
+                        </div>
+                        <div class="tag",>
+                             { format!("{}", value) }
+                        </div>
+                    </div>
+                </div>
+            </div>
+        }
+    };
+
+    // past 8 qubits only the sparse state or the tableau has it
+    let ket = |(n, value): (usize, qvm::Complex)| {
+        html! {
+            <div class="level",>
+                <div class="level-item",>
+                    <div class=("tags","has-addons"),>
+                        <div class=("tag","is-info"),>
+                             { sparse::fmt_ket(n, model.qvm.width()) }
+                        </div>
+                        <div class="tag",>
+                             { format!("{}", value) }
+                        </div>
+                    </div>
+                </div>
+            </div>
+        }
+    };
+    let (dense, observed) = if model.qvm.is_wide() {
+        // listed when the backend can, as the sparse state does
+        let kets = match model.qvm.backend().amplitudes() {
+            Some(amplitudes) => html! {
+                <div>
+                    { for amplitudes.into_iter().map(ket) }
+                </div>
+            },
+            None => html! { <div></div> },
+        };
+        (kets, html! { <div></div> })
+    } else {
+        let amplitudes = if model.exact {
+            match model.qvm.exact_state() {
+                Ok(state) => html! {
+                    <div>
+                        { for state.iter().enumerate().filter(|&(_, c)| !c.is_zero()).map(|(n, c)| exact_ket(n, c)) }
+                    </div>
+                },
+                Err(e) => html! {
+                    <div class="level",>
+                        <div class="level-item",>
+                            <div>{ format!("ERROR! {}", e) }</div>
+                        </div>
+                    </div>
+                },
+            }
+        } else {
+            html! {
+                <div>
+                    { for (0..model.qvm.state.len()).map(coeff) }
+                </div>
+            }
+        };
+        let dense = html! {
+            <div>
+                { amplitudes }
+                { bloch_spheres(model) }
+                { entanglement(model) }
+                { unitary(model) }
+            </div>
+        };
+        (dense, observables(model))
+    };
+    // why the last step stopped a continue, or why the conditions were not set
+    let stopped = match model.conditions_error {
+        Some(ref e) => format!("ERROR! {}", e),
+        None if model.qvm.triggered.is_empty() => "".to_string(),
+        None => {
+            let conditions = model.qvm.conditions();
+            let hit: Vec<String> = model.qvm.triggered.iter().map(|&i| conditions[i].to_string()).collect();
+            format!("after line {}: {}", model.qvm.counter, hit.join(", "))
+        }
+    };
+    let exact_label = if model.exact { "Float Amplitudes" } else { "Exact Amplitudes" };
     let lines = model.program.edit.to_string() + "\n \n";
     let program = match model.program.state {
         State::Ready => html! {
@@ -282,6 +1319,22 @@ fn view(model: &Model) -> Html<Msg> {
                         <button class="button", onclick=move|_| Msg::Prev,>{ "<" }</button>
                         <button class="button", onclick=move|_| Msg::Next,>{ ">" }</button>
                         <button class="button", onclick=move|_| Msg::End,>{ ">>" }</button>
+                        <button class="button", onclick=move|_| Msg::Continue,>{ "Continue" }</button>
+                        <button class="button", onclick=move|_| Msg::SaveSnapshot,>{ "Save Point" }</button>
+                        <button class="button", onclick=move|_| Msg::RestoreSnapshot,>{ "Back To Save Point" }</button>
+                        <button class="button", onclick=move|_| Msg::ToggleExact,>{ exact_label }</button>
+                    </div>
+                </div>
+                <div class="level",>
+                    <div class="level-item",>
+                        <div>{"Break When: "}</div>
+                        <textarea id="conditions", cols=30, rows=2,>{&model.conditions} </textarea>
+                        <button class="button", onclick=move|_| Msg::SetConditions,>{"Set"}</button>
+                    </div>
+                </div>
+                <div class="level",>
+                    <div class="level-item",>
+                        <div>{ stopped }</div>
                     </div>
                 </div>
 
@@ -294,9 +1347,9 @@ fn view(model: &Model) -> Html<Msg> {
                 <div class="level",>
                     <div class="level-item",>
                         <div class="content",>
-                            <ol>
+                            <ul>
                             { for lines.lines().enumerate().map(instruction) }
-                            </ol>
+                            </ul>
                         </div>
                     </div>
                 </div>
@@ -305,7 +1358,12 @@ fn view(model: &Model) -> Html<Msg> {
                         <div>{"Quantum State: "}</div>
                     </div>
                 </div>
-                { for (0..model.qvm.state.len()).map(coeff) }
+                { watches(model) }
+                { dense }
+                { backend(model) }
+                { stabilizers(model) }
+                { equivalence(model) }
+                { observed }
             </div>
         },
         State::Editing => html! {
@@ -351,6 +1409,7 @@ fn view(model: &Model) -> Html<Msg> {
                         { gates }
                     </div>
                 </div>
+                { inspector(model) }
                 { program }
 
             </div>
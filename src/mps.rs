@@ -0,0 +1,371 @@
+// Matrix product states for wide programs with little entanglement. Each qubit
+// holds a pair of left x right matrices, one per value it reads, and the
+// amplitude of a basis state is the product of the chosen matrices. Gates are
+// split back into sites by singular value decomposition, keeping at most
+// `bond` singular values.
+use float::{Float, ZERO_TOL};
+use linalg::eigh;
+use qvm::{deposit, is_zero, Complex, Matrix, C0, C1, NQ};
+use simulator::Simulator;
+
+#[derive(Clone)]
+pub struct Mps {
+    // sites[q][b] is the matrix for qubit q reading b; sites left of `center`
+    // are left orthonormal and those right of it right orthonormal
+    sites: Vec<Vec<Matrix>>,
+    center: usize,
+    bond: usize,
+}
+
+// a rows x cols product, unlike qvm::mul which is square only
+fn product(a: &Matrix, b: &Matrix) -> Matrix {
+    let cols = b.first().map_or(0, |row| row.len());
+    a.iter()
+        .map(|row| (0..cols).map(|j| row.iter().zip(b.iter()).map(|(x, r)| x * r[j]).sum()).collect())
+        .collect()
+}
+
+// rectangular adjoint, linalg::adjoint being square only
+fn dagger(m: &Matrix) -> Matrix {
+    let cols = m.first().map_or(0, |row| row.len());
+    (0..cols).map(|j| m.iter().map(|row| row[j].conj()).collect()).collect()
+}
+
+// m = u rest with u having orthonormal columns, truncated to the `bond`
+// largest singular values, and the fraction of the weight dropped
+fn left_split(m: &Matrix, bond: usize) -> (Matrix, Matrix, Float) {
+    let (vals, vecs) = eigh(&product(m, &dagger(m)));
+    let total: Float = vals.iter().sum();
+    let mut order: Vec<usize> = (0..vals.len()).collect();
+    order.sort_by(|&a, &b| vals[b].partial_cmp(&vals[a]).unwrap());
+    let keep: Vec<usize> = order
+        .iter()
+        .cloned()
+        .enumerate()
+        .filter(|&(k, i)| k == 0 || vals[i] > ZERO_TOL * total)
+        .map(|(_, i)| i)
+        .take(bond)
+        .collect();
+    let u: Matrix = vecs.iter().map(|row| keep.iter().map(|&i| row[i]).collect()).collect();
+    let rest = product(&dagger(&u), m);
+    let kept: Float = keep.iter().map(|&i| vals[i]).sum();
+    let dropped = if total > 0.0 { ((total - kept) / total).max(0.0) } else { 0.0 };
+    (u, rest, dropped)
+}
+
+fn swap_gate() -> Matrix {
+    let mut swap = vec![vec![C0; 4]; 4];
+    for &(i, j) in &[(0, 0), (1, 2), (2, 1), (3, 3)] {
+        swap[i][j] = C1;
+    }
+    swap
+}
+
+impl Mps {
+    // |0..0>, with every bond of dimension one
+    pub fn new(n: usize, bond: usize) -> Mps {
+        let site = vec![vec![vec![C1]], vec![vec![C0]]];
+        Mps {
+            sites: vec![site; n],
+            center: 0,
+            bond,
+        }
+    }
+    // the widest bond currently in use
+    pub fn max_bond(&self) -> usize {
+        self.sites.iter().map(|site| site[0].len()).max().unwrap_or(1)
+    }
+    fn stacked(&self, q: usize) -> Matrix {
+        // rows are (left, bit), columns right
+        let site = &self.sites[q];
+        (0..site[0].len())
+            .flat_map(|l| (0..2).map(move |b| site[b][l].clone()))
+            .collect()
+    }
+    fn unstack(stacked: &Matrix) -> Vec<Matrix> {
+        (0..2)
+            .map(|b| stacked.iter().enumerate().filter(|&(i, _)| i % 2 == b).map(|(_, row)| row.clone()).collect())
+            .collect()
+    }
+    fn move_center(&mut self, to: usize) {
+        // exact splits, so nothing is truncated on the way
+        while self.center < to {
+            let c = self.center;
+            let (u, rest, _) = left_split(&self.stacked(c), usize::max_value());
+            self.sites[c] = Mps::unstack(&u);
+            self.sites[c + 1] = (0..2).map(|b| product(&rest, &self.sites[c + 1][b])).collect();
+            self.center += 1;
+        }
+        while self.center > to {
+            let c = self.center;
+            // rows left, columns (bit, right)
+            let wide: Matrix = (0..self.sites[c][0].len())
+                .map(|l| self.sites[c][0][l].iter().chain(self.sites[c][1][l].iter()).cloned().collect())
+                .collect();
+            let (u, rest, _) = left_split(&dagger(&wide), usize::max_value());
+            let (v, rest) = (dagger(&u), dagger(&rest));
+            let right = self.sites[c][0][0].len();
+            self.sites[c] = (0..2)
+                .map(|b| v.iter().map(|row| row[b * right..(b + 1) * right].to_vec()).collect())
+                .collect();
+            self.sites[c - 1] = (0..2).map(|b| product(&self.sites[c - 1][b], &rest)).collect();
+            self.center -= 1;
+        }
+    }
+    // Applies `gate` on `qubits`, qubits[0] its most significant bit, and
+    // returns the fraction of the state's weight lost to truncation. Operands
+    // further apart are swapped next to each other and back again, so the
+    // cost stays with the gate's own width rather than the span it covers.
+    pub fn apply(&mut self, gate: &Matrix, qubits: &[usize]) -> Float {
+        let mut sorted = qubits.to_vec();
+        sorted.sort();
+        let lo = sorted[0];
+        // the i-th lowest operand moves down to lo + i, one neighbour at a time
+        let swaps: Vec<usize> = sorted
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &qb)| (lo + i..qb).rev())
+            .collect();
+        let moved: Vec<usize> = qubits
+            .iter()
+            .map(|qb| lo + sorted.iter().position(|s| s == qb).unwrap())
+            .collect();
+        let swap = swap_gate();
+        let mut kept = 1.0;
+        for &at in &swaps {
+            kept *= 1.0 - self.apply_block(&swap, &[at, at + 1]);
+        }
+        kept *= 1.0 - self.apply_block(gate, &moved);
+        for &at in swaps.iter().rev() {
+            kept *= 1.0 - self.apply_block(&swap, &[at, at + 1]);
+        }
+        1.0 - kept
+    }
+    // apply on qubits with none between them left out
+    fn apply_block(&mut self, gate: &Matrix, qubits: &[usize]) -> Float {
+        let lo = *qubits.iter().min().unwrap();
+        let hi = *qubits.iter().max().unwrap();
+        let m = hi - lo + 1;
+        self.move_center(lo);
+        // theta[l][p][r] over the span, qubit lo + j being bit j of p
+        let mut theta: Vec<Vec<Vec<Complex>>> = (0..self.sites[lo][0].len())
+            .map(|l| (0..2).map(|b| self.sites[lo][b][l].clone()).collect())
+            .collect();
+        for j in 1..m {
+            let site = &self.sites[lo + j];
+            theta = theta
+                .iter()
+                .map(|by_p| {
+                    (0..1 << (j + 1))
+                        .map(|p: usize| {
+                            let (low, b) = (p & ((1 << j) - 1), p >> j);
+                            product(&vec![by_p[low].clone()], &site[b]).remove(0)
+                        })
+                        .collect()
+                })
+                .collect();
+        }
+        let relative: Vec<usize> = qubits.iter().map(|qb| qb - lo).collect();
+        let offsets: Vec<usize> = (0..gate.len()).map(|local| deposit(local, &relative)).collect();
+        let mask = offsets[gate.len() - 1];
+        for by_p in theta.iter_mut() {
+            for r in 0..by_p[0].len() {
+                for base in (0..1 << m).filter(|p| p & mask == 0) {
+                    let before: Vec<Complex> = offsets.iter().map(|&o| by_p[base | o][r]).collect();
+                    for (row, &o) in gate.iter().zip(offsets.iter()) {
+                        by_p[base | o][r] = row.iter().zip(before.iter()).map(|(g, c)| g * c).sum();
+                    }
+                }
+            }
+        }
+        // split off one site at a time from the left
+        let mut kept = 1.0;
+        for j in 0..m - 1 {
+            let right = theta[0][0].len();
+            let rest_bits = m - 1 - j;
+            let stacked: Matrix = theta
+                .iter()
+                .flat_map(|by_p| {
+                    (0..2).map(move |b| {
+                        (0..1 << rest_bits)
+                            .flat_map(|rest: usize| by_p[b | rest << 1].iter().cloned())
+                            .collect()
+                    })
+                })
+                .collect();
+            let (u, rest, dropped) = left_split(&stacked, self.bond);
+            kept *= 1.0 - dropped;
+            self.sites[lo + j] = Mps::unstack(&u);
+            theta = rest
+                .iter()
+                .map(|row| (0..1 << rest_bits).map(|p| row[p * right..(p + 1) * right].to_vec()).collect())
+                .collect();
+        }
+        // the weight truncated away is reported rather than lost, so the norm
+        // only moves by what the gate itself does to it
+        let scale = 1.0 / kept.sqrt();
+        self.sites[hi] = (0..2)
+            .map(|b| theta.iter().map(|by_p| by_p[b].iter().map(|c| c * scale).collect()).collect())
+            .collect();
+        self.center = hi;
+        1.0 - kept
+    }
+    // the probability of reading 1 from `qb`
+    pub fn probability(&mut self, qb: usize) -> Float {
+        self.move_center(qb);
+        self.sites[qb][1].iter().flat_map(|row| row.iter()).map(|c| c.norm_sqr()).sum()
+    }
+    pub fn collapse(&mut self, qb: usize, outcome: bool) {
+        let keep = outcome as usize;
+        let p = if outcome { self.probability(qb) } else { 1.0 - self.probability(qb) };
+        if p <= 0.0 {
+            return;
+        }
+        let site = &mut self.sites[qb];
+        for row in site[1 - keep].iter_mut() {
+            for c in row.iter_mut() {
+                *c = C0;
+            }
+        }
+        for row in site[keep].iter_mut() {
+            for c in row.iter_mut() {
+                *c = *c / p.sqrt();
+            }
+        }
+    }
+}
+
+impl Simulator for Mps {
+    fn name(&self) -> &'static str {
+        "mps"
+    }
+    fn width(&self) -> usize {
+        self.sites.len()
+    }
+    fn apply(&mut self, _: &str, gate: &Matrix, qubits: &[usize]) -> Float {
+        Mps::apply(self, gate, qubits)
+    }
+    fn reversible(&self) -> bool {
+        false
+    }
+    fn probability(&mut self, qb: usize) -> Float {
+        Mps::probability(self, qb)
+    }
+    fn collapse(&mut self, qb: usize, outcome: bool) {
+        Mps::collapse(self, qb, outcome)
+    }
+    fn reset(&mut self) {
+        *self = Mps::new(self.sites.len(), self.bond);
+    }
+    fn amplitude(&self, n: usize) -> Option<Complex> {
+        let start = vec![vec![C1]];
+        let row = self.sites.iter().enumerate().fold(start, |acc, (q, site)| product(&acc, &site[n >> q & 1]));
+        Some(row[0][0])
+    }
+    // the other sites are orthonormal, so the weight is all at the center
+    fn norm(&self) -> Float {
+        let site = &self.sites[self.center];
+        site.iter().flat_map(|m| m.iter().flat_map(|row| row.iter())).map(|c| c.norm_sqr()).sum::<Float>().sqrt()
+    }
+    fn rescale(&mut self, factor: Float) {
+        for m in self.sites[self.center].iter_mut() {
+            for c in m.iter_mut().flat_map(|row| row.iter_mut()) {
+                *c = *c * factor;
+            }
+        }
+    }
+    // expanded on demand, and only for states narrow enough to list
+    fn amplitudes(&self) -> Option<Vec<(usize, Complex)>> {
+        if self.sites.len() > NQ {
+            return None;
+        }
+        let amplitudes = (0..1 << self.sites.len()).map(|n| (n, self.amplitude(n).unwrap()));
+        Some(amplitudes.filter(|&(_, c)| !is_zero(c)).collect())
+    }
+    fn snapshot(&self) -> Box<dyn Simulator> {
+        Box::new(self.clone())
+    }
+    fn status(&self) -> String {
+        format!("bond {} of {} in use", self.max_bond(), self.bond)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qvm::QVM;
+    use simulator::Backend;
+
+    #[test]
+    fn matches_dense() {
+        let prog = "h 0\nt 0\ncnot 0 4\nry(0.3) 2\nctrl @ h 2 0\nswap 3 1\ny 2\nh 5\ncnot 5 1\ntoffoli 4 5 3\n";
+        let mut qvm = QVM::new();
+        qvm.merge_gates(
+            "toffoli:\n1,0,0,0,0,0,0,0\n0,1,0,0,0,0,0,0\n0,0,1,0,0,0,0,0\n0,0,0,1,0,0,0,0\n0,0,0,0,1,0,0,0\n0,0,0,0,0,1,0,0\n0,0,0,0,0,0,0,1\n0,0,0,0,0,0,1,0",
+        )
+        .unwrap();
+        assert!(qvm.update(prog));
+        let mut mps = Mps::new(6, 16);
+        for inst in qvm.program.clone() {
+            let (expr, words) = inst.parts();
+            let qubits: Vec<usize> = words.iter().map(|qb| qb.parse().unwrap()).collect();
+            assert!(mps.apply(&qvm.resolve(expr).unwrap(), &qubits) < 1e-6);
+            qvm.next();
+        }
+        for n in 0..1 << 6 {
+            let c = mps.amplitude(n).unwrap();
+            assert!((qvm.state[n] - c).norm() < 1e-4, "{} vs {} at {}", qvm.state[n], c, n);
+        }
+        assert!((mps.probability(0) - 0.5).abs() < 1e-4);
+    }
+    #[test]
+    fn truncation() {
+        // a chain of entangled pairs needs bond 2 across each cut
+        let mut qvm = QVM::new();
+        let mut prog = String::new();
+        for q in 0..40 {
+            prog += &format!("ry(1.1) {}\ncnot {} {}\nrz(0.4) {}\n", q, q, q + 1, q + 1);
+        }
+        qvm.select_backend(Backend::Mps(2)).unwrap();
+        assert!(qvm.update(&prog));
+        while qvm.counter < qvm.program.len() {
+            qvm.next();
+        }
+        assert_eq!(qvm.backend().width(), 41);
+        assert_eq!(qvm.backend().status(), "bond 2 of 2 in use");
+        assert!(qvm.truncation.iter().all(|&t| t < 1e-4));
+        // a single bond cannot hold a bell pair
+        qvm.select_backend(Backend::Mps(1)).unwrap();
+        assert!(qvm.update("h 0\ncnot 0 1\n"));
+        qvm.next();
+        qvm.next();
+        assert!((qvm.truncation[1] - 0.5).abs() < 1e-4);
+        let total: Float = qvm.state.iter().map(|c| c.norm_sqr()).sum();
+        assert!((total - 1.0).abs() < 1e-4);
+    }
+    #[test]
+    fn far_apart() {
+        // the operands are swapped together, so this is as quick as cnot 0 1
+        let mut mps = Mps::new(20, 4);
+        let h = 1.0 / (2.0 as Float).sqrt();
+        let hadamard = vec![vec![Complex::new(h, 0.0); 2], vec![Complex::new(h, 0.0), Complex::new(-h, 0.0)]];
+        let cnot = vec![
+            vec![C1, C0, C0, C0],
+            vec![C0, C1, C0, C0],
+            vec![C0, C0, C0, C1],
+            vec![C0, C0, C1, C0],
+        ];
+        mps.apply(&hadamard, &[0]);
+        assert!(mps.apply(&cnot, &[0, 18]) < 1e-6);
+        // and the target is still 18, with every qubit between left alone
+        assert!((mps.amplitude(1 | 1 << 18).unwrap().re - h).abs() < 1e-4);
+        assert!((mps.amplitude(0).unwrap().re - h).abs() < 1e-4);
+        assert!((mps.probability(17)).abs() < 1e-4);
+        assert_eq!(mps.max_bond(), 2);
+        // control on the higher qubit
+        let mut mps = Mps::new(12, 4);
+        mps.apply(&hadamard, &[11]);
+        mps.apply(&cnot, &[11, 2]);
+        assert!((mps.amplitude(1 << 11 | 1 << 2).unwrap().re - h).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,179 @@
+// Peephole optimisation: identities are dropped, inverse pairs cancel and
+// rotations about the same axis merge, looking past gates that commute.
+use float::Float;
+use gatefmt;
+use linalg::{identity, phase_between};
+use qvm::{fmt_program, mul, parse_program, Instruction, Matrix, C0, TOL};
+
+pub struct Op {
+    pub inst: Instruction,
+    pub gate: Matrix,
+    pub qubits: Vec<usize>,
+    // 1-based line in the original program
+    pub line: usize,
+}
+
+fn show(inst: &Instruction) -> String {
+    fmt_program(&[inst.clone()]).trim_end().to_string()
+}
+
+// "rz(0.3)" as ("rz", 0.3)
+fn rotation(expr: &str) -> Option<(&str, Float)> {
+    let axis = expr.splitn(2, '(').next().unwrap();
+    if expr.contains(' ') || !expr.ends_with(')') || !(axis == "rx" || axis == "ry" || axis == "rz") {
+        return None;
+    }
+    gatefmt::parse_complex(&expr[axis.len() + 1..expr.len() - 1])
+        .ok()
+        .map(|angle| (axis, angle.re))
+}
+
+// `gate` on `qubits` as a matrix over all of `union`, union[0] the highest bit
+fn embed(gate: &Matrix, qubits: &[usize], union: &[usize]) -> Matrix {
+    let n = union.len();
+    let bits: Vec<usize> = qubits
+        .iter()
+        .map(|qb| n - 1 - union.iter().position(|q| q == qb).unwrap())
+        .collect();
+    let k = bits.len();
+    let local = |index: usize| (0..k).fold(0, |acc, j| acc << 1 | (index >> bits[j] & 1));
+    let mask = bits.iter().fold(0, |acc, bit| acc | 1 << bit);
+    let mut ret = vec![vec![C0; 1 << n]; 1 << n];
+    for col in 0..1 << n {
+        for out in 0..1 << k {
+            let row = (0..k).fold(col & !mask, |acc, j| acc | (out >> (k - 1 - j) & 1) << bits[j]);
+            ret[row][col] = gate[out][local(col)];
+        }
+    }
+    ret
+}
+
+fn union(a: &Op, b: &Op) -> Vec<usize> {
+    let mut ret = a.qubits.clone();
+    ret.extend(b.qubits.iter().filter(|qb| !a.qubits.contains(qb)));
+    ret
+}
+
+fn commute(a: &Op, b: &Op) -> bool {
+    // exactly, not just up to phase; too wide pairs are assumed not to
+    let all = union(a, b);
+    if all.len() > 4 {
+        return false;
+    }
+    let (ea, eb) = (embed(&a.gate, &a.qubits, &all), embed(&b.gate, &b.qubits, &all));
+    let (ab, ba) = (mul(&ea, &eb), mul(&eb, &ea));
+    (0..ab.len()).all(|i| (0..ab.len()).all(|j| (ab[i][j] - ba[i][j]).norm() < TOL))
+}
+
+fn cancels(a: &Op, b: &Op) -> bool {
+    let all = union(a, b);
+    if all.len() > 4 {
+        return false;
+    }
+    let product = mul(&embed(&b.gate, &b.qubits, &all), &embed(&a.gate, &a.qubits, &all));
+    phase_between(&identity(product.len()), &product, TOL).is_some()
+}
+
+fn merged(a: &Op, b: &Op) -> Option<Op> {
+    // rotations about one axis on one qubit add up
+    let ((axis, x), (other, y)) = (rotation(a.inst.parts().0)?, rotation(b.inst.parts().0)?);
+    if axis != other || a.qubits != b.qubits {
+        return None;
+    }
+    let text = format!("{}({}) {}\n", axis, x + y, a.qubits[0]);
+    let inst = parse_program(&text)?.pop()?;
+    Some(Op {
+        inst,
+        gate: mul(&b.gate, &a.gate),
+        qubits: b.qubits.clone(),
+        line: b.line,
+    })
+}
+
+// One rewrite of the first op that has one, or None when nothing applies.
+fn rewrite(ops: &mut Vec<Op>) -> Option<String> {
+    for i in 0..ops.len() {
+        if phase_between(&identity(ops[i].gate.len()), &ops[i].gate, TOL).is_some() {
+            let op = ops.remove(i);
+            return Some(format!("line {}: '{}' is the identity", op.line, show(&op.inst)));
+        }
+        // slide ops[i] forward past everything it commutes with
+        for j in i + 1..ops.len() {
+            if ops[j].qubits.iter().all(|qb| !ops[i].qubits.contains(qb)) {
+                continue;
+            }
+            if cancels(&ops[i], &ops[j]) {
+                let second = ops.remove(j);
+                let first = ops.remove(i);
+                return Some(format!(
+                    "lines {} and {}: '{}' and '{}' cancel",
+                    first.line,
+                    second.line,
+                    show(&first.inst),
+                    show(&second.inst)
+                ));
+            }
+            if let Some(op) = merged(&ops[i], &ops[j]) {
+                let note = format!(
+                    "lines {} and {}: merged into '{}'",
+                    ops[i].line,
+                    ops[j].line,
+                    show(&op.inst)
+                );
+                ops[j] = op;
+                ops.remove(i);
+                return Some(note);
+            }
+            if !commute(&ops[i], &ops[j]) {
+                break;
+            }
+        }
+    }
+    None
+}
+
+// Rewrites until none applies, with a note for each.
+pub fn peephole(mut ops: Vec<Op>) -> (Vec<Op>, Vec<String>) {
+    let mut notes = Vec::new();
+    while let Some(note) = rewrite(&mut ops) {
+        notes.push(note);
+    }
+    (ops, notes)
+}
+
+#[cfg(test)]
+mod tests {
+    use qvm::{fmt_program, QVM};
+
+    #[test]
+    fn redundant_pairs() {
+        let mut qvm = QVM::new();
+        let prog = "h 0
+h 0
+cnot 0 1
+z 2
+cnot 0 1
+rz(0.25) 1
+x 3
+rz(0.5) 1
+i1 2
+t 0
+cz 0 1
+dagger t 0
+swap 4 5
+swap 5 4
+";
+        assert!(qvm.update(prog));
+        let (program, removed) = qvm.optimize().unwrap();
+        assert_eq!(fmt_program(&program), "z 2\nx 3\nrz(0.75) 1\ncz 0 1\n");
+        assert_eq!(removed.len(), 6);
+        assert_eq!(removed[0], "lines 1 and 2: 'h 0' and 'h 0' cancel");
+        assert!(removed.iter().any(|note| note.starts_with("line 9: 'i1 2'")));
+
+        // nothing to do past a gate that does not commute
+        assert!(qvm.update("h 0\nx 0\nh 0\n"));
+        let (program, removed) = qvm.optimize().unwrap();
+        assert_eq!(program.len(), 3);
+        assert!(removed.is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+use float::{Float, ZERO_TOL};
+use qvm::{Complex, Matrix, Qstate, C0, C1, CI, NQ};
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Pauli {
+    I,
+    X,
+    Y,
+    Z,
+}
+
+impl Pauli {
+    fn parse(c: char) -> Option<Pauli> {
+        match c.to_ascii_uppercase() {
+            'I' => Some(Pauli::I),
+            'X' => Some(Pauli::X),
+            'Y' => Some(Pauli::Y),
+            'Z' => Some(Pauli::Z),
+            _ => None,
+        }
+    }
+}
+
+// a tensor product of single qubit paulis, identity on every qubit not listed
+#[derive(Clone, PartialEq, Debug)]
+pub struct PauliString {
+    pub ops: Vec<(usize, Pauli)>,
+}
+
+// a real weighted sum of pauli strings, eg. a hamiltonian
+#[derive(Clone, PartialEq, Debug)]
+pub struct Observable {
+    pub terms: Vec<(Float, PauliString)>,
+}
+
+impl PauliString {
+    // Accepts either a dense string written in ket order, so the rightmost
+    // letter is qubit 0 ("ZZIIIIII" is Z7 Z6), or sparse words like "X0 Y3".
+    pub fn parse(text: &str) -> Result<PauliString, String> {
+        let mut ops = Vec::new();
+        for word in text.split_whitespace() {
+            let mut chars = word.chars();
+            let first = chars.next().unwrap();
+            let rest = chars.as_str();
+            if !rest.is_empty() && rest.chars().all(|c| c.is_digit(10)) {
+                let op = Pauli::parse(first).ok_or(format!("unknown pauli '{}'", first))?;
+                let qb = usize::from_str_radix(rest, 10).map_err(|_| format!("bad qubit '{}'", rest))?;
+                ops.push((qb, op));
+            } else {
+                let len = word.chars().count();
+                if len > NQ {
+                    return Err(format!("'{}' is longer than {} qubits", word, NQ));
+                }
+                for (i, c) in word.chars().enumerate() {
+                    let op = Pauli::parse(c).ok_or(format!("unknown pauli '{}'", c))?;
+                    ops.push((len - 1 - i, op));
+                }
+            }
+        }
+        ops.retain(|&(_, op)| op != Pauli::I);
+        ops.sort_by_key(|&(qb, _)| qb);
+        for pair in ops.windows(2) {
+            if pair[0].0 == pair[1].0 {
+                return Err(format!("qubit {} appears twice", pair[0].0));
+            }
+        }
+        if let Some(&(qb, _)) = ops.last() {
+            if qb >= NQ {
+                return Err(format!("qubit {} out of range", qb));
+            }
+        }
+        Ok(PauliString { ops })
+    }
+
+    // P|i> = phase (-1)^|i & zmask| |i ^ flip>
+    fn masks(&self) -> (usize, usize, Complex) {
+        let mut flip = 0;
+        let mut zmask = 0;
+        let mut ys = 0;
+        for &(qb, op) in &self.ops {
+            match op {
+                Pauli::I => {}
+                Pauli::X => flip |= 1 << qb,
+                Pauli::Y => {
+                    flip |= 1 << qb;
+                    zmask |= 1 << qb;
+                    ys += 1;
+                }
+                Pauli::Z => zmask |= 1 << qb,
+            }
+        }
+        (flip, zmask, [C1, CI, -C1, -CI][ys % 4])
+    }
+
+    // <psi|P|psi>, real since P is hermitian
+    pub fn expectation(&self, state: &Qstate) -> Float {
+        let (flip, zmask, phase) = self.masks();
+        let mut total = C0;
+        for (i, amp) in state.iter().enumerate() {
+            let term = state[i ^ flip].conj() * amp;
+            if (i & zmask).count_ones() % 2 == 0 {
+                total += term;
+            } else {
+                total -= term;
+            }
+        }
+        (phase * total).re
+    }
+
+    // ket order letters over n qubits, eg. "IXZ" for X1 Z0
+    pub fn dense(&self, n: usize) -> String {
+        (0..n)
+            .rev()
+            .map(|qb| match self.ops.iter().find(|&&(q, _)| q == qb) {
+                Some(&(_, op)) => format!("{:?}", op),
+                None => "I".to_string(),
+            })
+            .collect()
+    }
+}
+
+// U = sum_P c_P P with c_P = tr(P U) / 2^n, zero terms dropped
+pub fn decompose(m: &Matrix) -> Vec<(Complex, PauliString)> {
+    let dim = m.len();
+    let n = dim.trailing_zeros() as usize;
+    let mut ret = Vec::new();
+    for code in 0..dim * dim {
+        // two bits per qubit pick I, X, Y or Z
+        let ops = (0..n)
+            .map(|qb| (qb, [Pauli::I, Pauli::X, Pauli::Y, Pauli::Z][code >> (2 * qb) & 3]))
+            .filter(|&(_, op)| op != Pauli::I)
+            .collect();
+        let string = PauliString { ops };
+        let (flip, zmask, phase) = string.masks();
+        let mut trace = C0;
+        for j in 0..dim {
+            let sign = if (j & zmask).count_ones() % 2 == 0 { 1.0 } else { -1.0 };
+            trace += m[j][j ^ flip] * sign;
+        }
+        // P is hermitian, so tr(P U) = tr(P^dagger U)
+        let coeff = phase * trace / dim as Float;
+        if coeff.norm() > ZERO_TOL {
+            ret.push((coeff, string));
+        }
+    }
+    ret
+}
+
+impl fmt::Display for PauliString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.ops.is_empty() {
+            return write!(f, "I");
+        }
+        let words: Vec<String> = self.ops.iter().map(|(qb, op)| format!("{:?}{}", op, qb)).collect();
+        write!(f, "{}", words.join(" "))
+    }
+}
+
+impl Observable {
+    // Terms are separated by + or -, each with an optional coefficient:
+    // "0.5 ZZIIIIII - 1.2 * X0 Y3 + 0.25"
+    pub fn parse(text: &str) -> Result<Observable, String> {
+        let mut pieces = Vec::new();
+        let mut current = String::new();
+        for c in text.chars() {
+            let last = current.trim_end().chars().last();
+            let splits = match last {
+                Some(l) => l != 'e' && l != 'E' && l != '*' && l != '+' && l != '-',
+                None => false,
+            };
+            if (c == '+' || c == '-') && splits {
+                pieces.push(current);
+                current = String::new();
+            }
+            current.push(c);
+        }
+        pieces.push(current);
+
+        let mut terms = Vec::new();
+        for piece in pieces {
+            let piece = piece.replace('*', " ");
+            let mut body = piece.trim();
+            let mut coeff = 1.0;
+            if body.starts_with('+') {
+                body = body[1..].trim_start();
+            } else if body.starts_with('-') && !body[1..].starts_with(|c: char| c.is_digit(10) || c == '.') {
+                coeff = -1.0;
+                body = body[1..].trim_start();
+            }
+            if body.is_empty() {
+                return Err("empty term".into());
+            }
+            let mut words = body.splitn(2, char::is_whitespace);
+            let first = words.next().unwrap();
+            if let Ok(value) = first.parse::<Float>() {
+                coeff *= value;
+                body = words.next().unwrap_or("");
+            }
+            terms.push((coeff, PauliString::parse(body)?));
+        }
+        Ok(Observable { terms })
+    }
+
+    pub fn expectation(&self, state: &Qstate) -> Float {
+        self.terms
+            .iter()
+            .map(|(coeff, string)| coeff * string.expectation(state))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qvm::{eq, QVM};
+
+    fn run(prog: &str) -> QVM {
+        let mut qvm = QVM::new();
+        qvm.update(prog);
+        while qvm.counter < qvm.program.len() {
+            qvm.next();
+        }
+        qvm
+    }
+    #[test]
+    fn parse_forms() {
+        let dense = PauliString::parse("ZZIIIIII").unwrap();
+        assert_eq!(dense.ops, vec![(6, Pauli::Z), (7, Pauli::Z)]);
+        let sparse = PauliString::parse("Y3 X0").unwrap();
+        assert_eq!(sparse.ops, vec![(0, Pauli::X), (3, Pauli::Y)]);
+        assert!(PauliString::parse("X0 Z0").is_err());
+        assert!(PauliString::parse("X9").is_err());
+
+        let obs = Observable::parse("0.5 ZZ - 1e-1 * X0 Y3 + 2").unwrap();
+        assert_eq!(obs.terms.len(), 3);
+        assert!(eq(obs.terms[0].0, 0.5));
+        assert!(eq(obs.terms[1].0, -0.1));
+        assert!(obs.terms[2].1.ops.is_empty());
+        assert!(Observable::parse("0.5 ZQ").is_err());
+    }
+    #[test]
+    fn bell_correlators() {
+        let qvm = run("h 0\ncnot 0 1");
+        let exp = |s: &str| Observable::parse(s).unwrap().expectation(&qvm.state);
+        assert!((exp("Z0 Z1") - 1.0).abs() < 1e-5);
+        assert!((exp("X0 X1") - 1.0).abs() < 1e-5);
+        assert!((exp("Y0 Y1") + 1.0).abs() < 1e-5);
+        assert!(exp("Z0").abs() < 1e-5);
+        assert!((exp("0.5 XX - YY + 0.25") - 1.75).abs() < 1e-5);
+    }
+    #[test]
+    fn single_qubit_axes() {
+        let qvm = run("h 2");
+        let exp = |s: &str| Observable::parse(s).unwrap().expectation(&qvm.state);
+        assert!((exp("X2") - 1.0).abs() < 1e-5);
+        assert!(exp("Z2").abs() < 1e-5);
+        assert!((exp("Z0") - 1.0).abs() < 1e-5);
+    }
+    #[test]
+    fn gate_decomposition() {
+        let mut qvm = QVM::new();
+        qvm.merge_gates("s:\n1, 0\n0, i").unwrap();
+        let terms = |expr: &str| -> Vec<(Complex, String)> {
+            decompose(&qvm.resolve(expr).unwrap())
+                .into_iter()
+                .map(|(c, p)| (c, p.dense(2)))
+                .collect()
+        };
+        let h = terms("h");
+        assert_eq!(h.len(), 2);
+        assert!(eq(h[0].0.re, 1.0 / (2.0 as Float).sqrt()) && h[0].1 == "IX");
+        let s = terms("s");
+        assert!((s[1].0 - Complex::new(0.5, -0.5)).norm() < 1e-6 && s[1].1 == "IZ");
+        // cnot = (II + IX + ZI - ZX) / 2 with the control first
+        let cnot = terms("cnot");
+        let names: Vec<&str> = cnot.iter().map(|t| t.1.as_str()).collect();
+        assert_eq!(names, vec!["II", "IX", "ZI", "ZX"]);
+        assert!(eq(cnot[3].0.re, -0.5));
+    }
+}
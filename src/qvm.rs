@@ -1,19 +1,39 @@
+use cliffordt::{Approximation, Net};
+use condition::{named_state, Condition, Quantity};
+use density::{self, Bloch, Link};
+use exact::{self, Exact, ExactMatrix};
+use float::{Float, ZERO_TOL};
+use gatefmt;
+use inspect::{self, Inspection};
+use kak;
+use linalg;
+use mps::Mps;
 use num_complex;
+use optimize::{self, Op};
+use pauli::Observable;
+use simulator::{Backend, Dense, Simulator};
+use sparse::SparseState;
+use stabilizer::{self, Tableau};
+use transpile;
 use serde_json;
-use std::collections::BTreeMap;
-use std::f32::EPSILON;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::iter::FromIterator;
-use std::cmp::{min, max};
 
-type Complex = num_complex::Complex32;
+pub type Complex = num_complex::Complex<Float>;
 
 pub fn is_zero(c: Complex) -> bool {
-    c.re.abs() < EPSILON && c.im.abs() < EPSILON
+    c.re.abs() < ZERO_TOL && c.im.abs() < ZERO_TOL
 }
 
-pub fn eq(a: f32, b: f32) -> bool {
-    (a - b).abs() < EPSILON
+pub use float::TOL;
+
+// loose enough for hand typed entries like 0.707
+pub const UNITARY_TOL: Float = 1e-3;
+
+pub fn eq(a: Float, b: Float) -> bool {
+    (a - b).abs() < ZERO_TOL
 }
 fn pow(a: usize, b: usize) -> usize {
     let mut prod = 1;
@@ -23,27 +43,92 @@ fn pow(a: usize, b: usize) -> usize {
     prod
 }
 const S: usize = 256; // 2 ^ 8
-const NQ: usize = 8;
+pub const NQ: usize = 8;
+// widest program run on the sparse state, for anything past NQ qubits
+pub const SPARSE_NQ: usize = 30;
+// widest program run on a matrix product state, when that backend is selected
+pub const MPS_NQ: usize = 64;
+// widest all clifford program, run on the stabilizer tableau alone
+pub const STABILIZER_NQ: usize = 1024;
 
-type Qstate = Vec<Complex>;
-type Gate = Vec<Vec<Complex>>;
+pub type Qstate = Vec<Complex>;
+pub type Matrix = Vec<Vec<Complex>>;
+type Gate = Matrix;
 
-#[derive(Serialize, PartialEq)]
+#[derive(Serialize, PartialEq, Clone, Debug)]
 pub enum Instruction {
     Malformed,
     Single(String, String),
     Double(String, String, String),
-    // TODO Triple Qubit Gate
+    Multi(String, Vec<String>),
+}
+
+impl Instruction {
+    // the gate expression, eg. "ctrl @ x", and the qubit words it acts on
+    pub fn parts(&self) -> (&str, Vec<&str>) {
+        match self {
+            Instruction::Single(gate, qb) => (gate, vec![qb]),
+            Instruction::Double(gate, qb0, qb1) => (gate, vec![qb0, qb1]),
+            Instruction::Multi(gate, qbs) => (gate, qbs.iter().map(|qb| qb.as_str()).collect()),
+            Instruction::Malformed => ("", vec![]),
+        }
+    }
+}
+
+pub enum Equivalence {
+    Same { phase: Complex },
+    // a basis input whose outputs disagree, with (output, ours, theirs) amplitudes
+    Differs { input: usize, amplitudes: Vec<(usize, Complex, Complex)> },
 }
 
 pub struct QVM {
     pub counter: usize,
+    // the backend's amplitudes for programs on up to NQ qubits, which the
+    // analyses read; |0> otherwise
     pub state: Qstate,
     pub program: Vec<Instruction>,
+    // the weight each line lost to truncation, zero for exact backends
+    pub truncation: Vec<Float>,
+    // how far each line that has run moved the state's norm, which a gate
+    // that is not quite unitary does
+    pub drift: Vec<Float>,
+    // the outcome of each measurement line that has run, replayed on the way back
+    pub outcomes: Vec<Option<bool>>,
+    drift_tol: Float,
+    renormalise: bool,
+    // lines that resume stops before, counted from 0
+    breakpoints: BTreeSet<usize>,
+    // predicates that stop resume on the step where they become true
+    conditions: Vec<Condition>,
+    // the conditions that became true on the last step forward, by index
+    pub triggered: Vec<usize>,
+    // quantities read again after every step, which outlive program changes
+    watches: Vec<(String, Quantity)>,
+    pub watched: Vec<Result<Complex, String>>,
+    // mutual information and concurrence of every pair, worked out when first
+    // asked for after a step
+    links: RefCell<Option<Vec<Link>>>,
+    // the clifford+t net, built on the first approximation
+    net: RefCell<Option<Net>>,
     gates: BTreeMap<String, Gate>,
+    width: usize,
+    selected: Backend,
+    backend: Box<dyn Simulator>,
+    seed: u64,
+}
+
+// everything a run depends on, to come back to later with QVM::restore
+pub struct Snapshot {
+    program: Vec<Instruction>,
+    counter: usize,
+    truncation: Vec<Float>,
+    drift: Vec<Float>,
+    outcomes: Vec<Option<bool>>,
+    backend: Box<dyn Simulator>,
+    seed: u64,
 }
 
-fn mul(this: &Gate, other: &Gate) -> Gate {
+pub fn mul(this: &Gate, other: &Gate) -> Gate {
     // matrix multiplication
     let size = this.len();
     assert!(size == other.len());
@@ -61,7 +146,7 @@ fn mul(this: &Gate, other: &Gate) -> Gate {
 }
 pub const C0: Complex = Complex { re: 0.0, im: 0.0 };
 pub const C1: Complex = Complex { re: 1.0, im: 0.0 };
-const CI: Complex = Complex { re: 0.0, im: 1.0 };
+pub const CI: Complex = Complex { re: 0.0, im: 1.0 };
 
 type G1 = [[Complex; 2]; 2];
 type G2 = [[Complex; 4]; 4];
@@ -103,6 +188,29 @@ fn vecify2(a: G2) -> Gate {
     }
     outer
 }
+// Custom gates in the plain text format from show_gates or the json form,
+// each validated, none changing a builtin.
+fn parse_gates(gates: &str) -> Result<BTreeMap<String, Gate>, String> {
+    let gates: BTreeMap<String, Gate> = if gates.trim_start().starts_with('{') {
+        serde_json::from_str(gates).map_err(|e| e.to_string())?
+    } else {
+        gatefmt::parse_gates(gates)?
+    };
+    let defaults = standard_gates();
+    for (name, gate) in &gates {
+        validate_gate(name, gate)?;
+        if let Some(builtin) = defaults.get(name) {
+            let differs = builtin
+                .iter()
+                .zip(gate.iter())
+                .any(|(a, b)| a.iter().zip(b.iter()).any(|(x, y)| (x - y).norm() > TOL));
+            if builtin.len() != gate.len() || differs {
+                return Err(format!("gate '{}' is builtin and cannot be changed", name));
+            }
+        }
+    }
+    Ok(gates)
+}
 fn standard_gates() -> BTreeMap<String, Gate> {
     let mut map = BTreeMap::new();
     map.insert("x".into(), vecify([[C0, C1], [C1, C0]]));
@@ -111,6 +219,10 @@ fn standard_gates() -> BTreeMap<String, Gate> {
 
     let h = 1.0 / Complex { re: 2.0, im: 0.0 }.sqrt();
     map.insert("h".into(), vecify([[h, h], [h, -h]]));
+    map.insert("s".into(), vecify([[C1, C0], [C0, CI]]));
+    map.insert("t".into(), vecify([[C1, C0], [C0, h + h * CI]]));
+    let (p, m) = (Complex::new(0.5, 0.5), Complex::new(0.5, -0.5));
+    map.insert("sx".into(), vecify([[p, m], [m, p]]));
     map.insert("i1".into(), vecify(I1));
     map.insert("i2".into(), vecify2(I2));
     map.insert(
@@ -122,6 +234,15 @@ fn standard_gates() -> BTreeMap<String, Gate> {
             [C0, C0, C1, C0],
         ]),
     );
+    map.insert(
+        "cz".into(),
+        vecify2([
+            [C1, C0, C0, C0],
+            [C0, C1, C0, C0],
+            [C0, C0, C1, C0],
+            [C0, C0, C0, -C1],
+        ]),
+    );
     map.insert("swap".into(), vecify2(SWAP));
     map
 }
@@ -134,21 +255,80 @@ fn zero() -> Qstate {
     ret
 }
 
-fn dot_product(gate: &Gate, state: &Qstate) -> Qstate {
-    assert!(gate.len() == state.len());
-    let mut ret = Vec::new();
-    for _ in 0..S {
-        ret.push(C0);
+// scatter the bits of `local` onto `qubits`, qubits[0] taking the highest bit
+pub fn deposit(local: usize, qubits: &[usize]) -> usize {
+    let k = qubits.len();
+    let mut ret = 0;
+    for (j, qb) in qubits.iter().enumerate() {
+        if local >> (k - 1 - j) & 1 == 1 {
+            ret |= 1 << qb;
+        }
     }
-    for (i, row) in gate.iter().enumerate() {
-        for (j, item) in row.iter().enumerate() {
-            ret[i] += item * state[j];
+    ret
+}
+
+pub fn apply_gate(gate: &Gate, qubits: &[usize], state: &Qstate) -> Qstate {
+    // act on the 2^k amplitudes sharing each setting of the untouched qubits,
+    // instead of building the full 2^n x 2^n lifted matrix
+    let dim = gate.len();
+    assert!(dim == 1 << qubits.len());
+    let offsets: Vec<usize> = (0..dim).map(|local| deposit(local, qubits)).collect();
+    let mask = offsets[dim - 1];
+    let mut ret = state.clone();
+    for base in (0..state.len()).filter(|i| i & mask == 0) {
+        for (r, row) in gate.iter().enumerate() {
+            let mut val = C0;
+            for (c, item) in row.iter().enumerate() {
+                val += item * state[base | offsets[c]];
+            }
+            ret[base | offsets[r]] = val;
         }
     }
     ret
 }
 
-fn tensor_product(a: &Gate, b: &Gate) -> Gate {
+// gate^k by repeated squaring, with the adjoint as the inverse for k < 0
+fn power<M>(gate: M, k: i32, one: M, adjoint: &dyn Fn(&M) -> M, mul: &dyn Fn(&M, &M) -> M) -> M {
+    let mut base = if k < 0 { adjoint(&gate) } else { gate };
+    // i32::MIN has no i32 absolute value
+    let mut e = (k as i64).abs();
+    let mut ret = one;
+    while e > 0 {
+        if e & 1 == 1 {
+            ret = mul(&base, &ret);
+        }
+        e >>= 1;
+        if e > 0 {
+            base = mul(&base, &base);
+        }
+    }
+    ret
+}
+
+// the unitary of gates applied in turn, column j being the image of |j>
+fn product(ops: &[(Gate, Vec<usize>)]) -> Matrix {
+    let mut columns = Vec::new();
+    for j in 0..S {
+        let mut col = vec![C0; S];
+        col[j] = C1;
+        for &(ref gate, ref qubits) in ops {
+            col = apply_gate(gate, qubits, &col);
+        }
+        columns.push(col);
+    }
+    (0..S).map(|i| (0..S).map(|j| columns[j][i]).collect()).collect()
+}
+
+// the column for basis state `start` under already resolved exact gates
+fn exact_run(ops: &[(ExactMatrix, Vec<usize>)], start: usize) -> Vec<Exact> {
+    let mut state = vec![exact::ZERO; S];
+    state[start] = exact::ONE;
+    for &(ref gate, ref qubits) in ops {
+        state = exact::apply_gate(gate, qubits, &state);
+    }
+    state
+}
+pub fn tensor_product(a: &Gate, b: &Gate) -> Gate {
     let b_dim = b.len();
     let dim = b_dim * a.len();
     let mut mat = Vec::new();
@@ -173,123 +353,938 @@ fn tensor_product(a: &Gate, b: &Gate) -> Gate {
     mat
 }
 
-fn lift_gate(n: usize, gate: &Gate) -> Gate {
-    let i1 = &vecify(I1);
+pub fn validate_gate(name: &str, gate: &Gate) -> Result<(), String> {
+    let dim = gate.len();
+    if dim < 2 || !dim.is_power_of_two() || dim > S {
+        return Err(format!("gate '{}' has {} rows, expected 2^k", name, dim));
+    }
+    if let Some((i, row)) = gate.iter().enumerate().find(|(_, row)| row.len() != dim) {
+        return Err(format!("gate '{}' row {} has {} entries, expected {}", name, i, row.len(), dim));
+    }
+    let deviation = unitary_deviation(gate);
+    if deviation > UNITARY_TOL {
+        return Err(format!("gate '{}' is not unitary, |U^dagger U - I| = {:.2e}", name, deviation));
+    }
+    Ok(())
+}
 
-    let mut arr = [i1; NQ];
-    arr[NQ-1-n] = gate;
+// largest entry of U^dagger U - I
+fn unitary_deviation(gate: &Gate) -> Float {
+    let dim = gate.len();
+    let mut deviation: Float = 0.0;
+    for i in 0..dim {
+        for j in 0..dim {
+            let mut val = C0;
+            for k in 0..dim {
+                val += gate[k][i].conj() * gate[k][j];
+            }
+            if i == j {
+                val -= C1;
+            }
+            deviation = deviation.max(val.norm());
+        }
+    }
+    deviation
+}
 
-    let start;
-    if gate.len() == 2 { // single qb
-        start = 0;
-    } else {
-        start = 1;
+fn parse_instruction(line: &str) -> Instruction {
+    // modifiers come before the gate name: "dagger g" or "ctrl @ g", "inv @ g", "pow(k) @ g"
+    let line = line.replace('@', " @ ");
+    let words = line.split_whitespace().collect::<Vec<&str>>();
+    let mut name = 0;
+    while name < words.len() {
+        if words[name] == "dagger" {
+            name += 1;
+        } else if words.get(name + 1) == Some(&"@") {
+            name += 2;
+        } else {
+            break;
+        }
     }
-    let mut prod = (*arr[start]).clone();
-    for i in start+1..NQ {
-        prod = tensor_product(&prod, arr[i]);
+    if name >= words.len() {
+        return Instruction::Malformed;
     }
-    prod
+    let gate = words[..name + 1].join(" ");
+    let qubits = &words[name + 1..];
+    match qubits.len() {
+        1 => Instruction::Single(gate, qubits[0].into()),
+        2 => Instruction::Double(gate, qubits[0].into(), qubits[1].into()),
+        0 => Instruction::Malformed,
+        _ => Instruction::Multi(gate, qubits.iter().map(|&qb| qb.into()).collect()),
+    }
+}
+
+pub fn fmt_program(program: &[Instruction]) -> String {
+    String::from_iter(program.iter().map(|inst| match inst {
+        Instruction::Single(gate, qb) => gate.clone() + " " + qb + "\n",
+        Instruction::Double(gate, a1, a2) => gate.clone() + " " + a1 + " " + a2 + "\n",
+        Instruction::Multi(gate, qbs) => gate.clone() + " " + &qbs.join(" ") + "\n",
+        _ => "".into(),
+    }))
 }
 
-fn join_gates(gates: Vec<&Gate>) -> Gate {
-    // [a,b,c] -> a*b*c*b*a
-    let size = gates.len();
-    let mut ret = gates[0].clone();
-    for i in 1..size {
-        ret = mul(gates[i], &ret);
+fn rotation(name: &str) -> Result<Gate, String> {
+    // parametric rx(angle), ry(angle) and rz(angle), eg. "rz(pi/4)"
+    let axis = name.splitn(2, '(').next().unwrap();
+    if !name.ends_with(')') || axis.len() == name.len() {
+        return Err(format!("unknown gate '{}'", name));
     }
-    for i in 1..size {
-        ret = mul(gates[size-1-i], &ret);
+    let arg = &name[axis.len() + 1..name.len() - 1];
+    let angle = gatefmt::parse_complex(arg)?;
+    if angle.im.abs() > TOL {
+        return Err(format!("'{}' needs a real angle", name));
+    }
+    match axis {
+        "rx" => Ok(linalg::rx(angle.re)),
+        "ry" => Ok(linalg::ry(angle.re)),
+        "rz" => Ok(linalg::rz(angle.re)),
+        _ => Err(format!("unknown gate '{}'", name)),
+    }
+}
+
+pub fn controlled(gate: &Gate) -> Gate {
+    // the control is the most significant, ie. first listed, qubit
+    let dim = gate.len();
+    let mut ret = linalg::identity(2 * dim);
+    for (i, row) in gate.iter().enumerate() {
+        for (j, item) in row.iter().enumerate() {
+            ret[dim + i][dim + j] = *item;
+        }
     }
     ret
 }
+
+pub fn parse_program(program: &str) -> Option<Vec<Instruction>> {
+    let prog = program
+        .lines()
+        .map(parse_instruction)
+        .collect::<Vec<Instruction>>();
+    if prog.contains(&Instruction::Malformed) {
+        None
+    } else {
+        Some(prog)
+    }
+}
+
 impl QVM {
     pub fn new() -> QVM {
         QVM {
             counter: 0,
             state: zero(),
             program: vec![],
+            truncation: vec![],
+            drift: vec![],
+            outcomes: vec![],
+            drift_tol: TOL,
+            renormalise: false,
+            breakpoints: BTreeSet::new(),
+            conditions: vec![],
+            triggered: vec![],
+            watches: vec![],
+            watched: vec![],
+            links: RefCell::new(None),
+            net: RefCell::new(None),
             gates: standard_gates(),
+            width: 1,
+            selected: Backend::Auto,
+            backend: Box::new(Dense::new(1)),
+            seed: 0x2545_f491_4f6c_dd1d,
         }
     }
     pub fn reset(&mut self) {
         self.counter = 0;
+        self.restart();
+        self.outcomes = vec![None; self.program.len()];
+        self.refresh();
+    }
+    fn restart(&mut self) {
+        self.backend.reset();
+        self.truncation = vec![0.0; self.program.len()];
+        self.drift = vec![0.0; self.program.len()];
+        self.triggered = vec![];
+    }
+    // Once after the lines a call runs rather than after every one of them,
+    // since the watches are read again here.
+    fn refresh(&mut self) {
+        // the dense view of whatever the backend holds
         self.state = zero();
+        self.links = RefCell::new(None);
+        if !self.is_wide() {
+            if let Some(amplitudes) = self.backend.amplitudes() {
+                self.state = vec![C0; S];
+                for (n, c) in amplitudes {
+                    self.state[n] = c;
+                }
+            }
+        }
+        let watches = self.watches.clone();
+        self.watched = watches.iter().map(|&(_, ref quantity)| self.quantity(quantity)).collect();
+    }
+    fn build_backend(&self) -> Box<dyn Simulator> {
+        let clifford = self.is_clifford(&self.program);
+        match self.selected {
+            Backend::Auto if self.width <= NQ => Box::new(Dense::new(self.width)),
+            Backend::Auto if clifford => Box::new(Tableau::new(self.width)),
+            Backend::Auto | Backend::Sparse => Box::new(SparseState::new(self.width)),
+            Backend::Dense => Box::new(Dense::new(self.width)),
+            Backend::Mps(bond) => Box::new(Mps::new(self.width, bond)),
+            Backend::Stabilizer => Box::new(Tableau::new(self.width)),
+        }
+    }
+    // Runs the program on `backend` from now on, if it can hold it.
+    pub fn select_backend(&mut self, backend: Backend) -> Result<(), String> {
+        let old = self.selected;
+        self.selected = backend;
+        if let Err(e) = self.check(&self.program) {
+            self.selected = old;
+            return Err(e);
+        }
+        self.backend = self.build_backend();
+        self.replay();
+        Ok(())
+    }
+    pub fn backend(&self) -> &dyn Simulator {
+        &*self.backend
+    }
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            program: self.program.clone(),
+            counter: self.counter,
+            truncation: self.truncation.clone(),
+            drift: self.drift.clone(),
+            outcomes: self.outcomes.clone(),
+            backend: self.backend.snapshot(),
+            seed: self.seed,
+        }
+    }
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        if snapshot.program != self.program || snapshot.backend.name() != self.backend.name() {
+            return Err("the program or backend has changed since the snapshot".into());
+        }
+        self.counter = snapshot.counter;
+        self.truncation = snapshot.truncation.clone();
+        self.drift = snapshot.drift.clone();
+        self.outcomes = snapshot.outcomes.clone();
+        self.backend = snapshot.backend.snapshot();
+        self.seed = snapshot.seed;
+        // like prev, nothing has just triggered
+        self.triggered = vec![];
+        self.refresh();
+        Ok(())
+    }
+    // The norm past which a state counts as drifted, reported by worst_drift
+    // and scaled back to 1 when renormalising.
+    pub fn set_drift_tolerance(&mut self, tol: Float) -> Result<(), String> {
+        if !(tol > 0.0) {
+            return Err(format!("bad tolerance '{}'", tol));
+        }
+        self.drift_tol = tol;
+        self.replay();
+        Ok(())
+    }
+    pub fn drift_tolerance(&self) -> Float {
+        self.drift_tol
+    }
+    pub fn set_renormalise(&mut self, renormalise: bool) {
+        self.renormalise = renormalise;
+        self.replay();
+    }
+    pub fn renormalising(&self) -> bool {
+        self.renormalise
+    }
+    pub fn norm(&self) -> Float {
+        self.backend.norm()
+    }
+    // the line that has run and moved the norm furthest, if past the tolerance
+    pub fn worst_drift(&self) -> Option<(usize, Float)> {
+        let worst = self.drift[..self.counter]
+            .iter()
+            .cloned()
+            .enumerate()
+            .max_by(|a, b| a.1.abs().partial_cmp(&b.1.abs()).unwrap());
+        match worst {
+            Some((i, drift)) if drift.abs() > self.drift_tol => Some((i, drift)),
+            _ => None,
+        }
     }
     pub fn read_program(&self) -> String {
-        String::from_iter(self.program.iter().map(|inst| match inst {
-            Instruction::Single(gate, qb) => gate.clone() + " " + qb + "\n",
-            Instruction::Double(gate, a1, a2) => gate.clone() + " " + a1 + " " + a2 + "\n",
-            _ => "".into(),
-        }))
+        fmt_program(&self.program)
     }
     pub fn update(&mut self, program: &str) -> bool {
-        let prog = program
-            .lines()
-            .map(|line| {
-                let words = line.split_whitespace().collect::<Vec<&str>>();
-                match words.len() {
-                    2 => Instruction::Single(words[0].into(), words[1].into()),
-                    3 => Instruction::Double(words[0].into(), words[1].into(), words[2].into()),
-                    _ => Instruction::Malformed,
+        match parse_program(program) {
+            Some(prog) => {
+                if self.check(&prog).is_err() {
+                    return false;
                 }
-            }).collect::<Vec<Instruction>>();
-        if prog.contains(&Instruction::Malformed) {
-            false
-        } else {
-            self.program = prog;
-            true
+                let widest = prog.iter().flat_map(|inst| self.qubits(inst, STABILIZER_NQ).unwrap()).max();
+                self.width = widest.map_or(1, |qb| qb + 1);
+                self.program = prog;
+                self.backend = self.build_backend();
+                self.outcomes = vec![None; self.program.len()];
+                self.counter = self.counter.min(self.program.len());
+                let len = self.program.len();
+                self.breakpoints = self.breakpoints.iter().cloned().filter(|&line| line < len).collect();
+                self.replay();
+                true
+            }
+            None => false,
         }
     }
-    pub fn set_gates(&mut self, gates: &str) -> bool {
-        match serde_json::from_str(gates) {
-            Ok(obj) => {
-                self.gates = obj;
-                true
+    pub fn check(&self, program: &[Instruction]) -> Result<(), String> {
+        // how wide a program the selected backend takes
+        let clifford = self.is_clifford(program);
+        let limit = match self.selected {
+            Backend::Auto if clifford => STABILIZER_NQ,
+            Backend::Auto | Backend::Sparse => SPARSE_NQ,
+            Backend::Dense => NQ,
+            Backend::Mps(_) => MPS_NQ,
+            Backend::Stabilizer if clifford => STABILIZER_NQ,
+            Backend::Stabilizer => {
+                return Err("the stabilizer backend only runs h, s, x, y, z, cnot, cz, swap and measure".into())
+            }
+        };
+        for (i, inst) in program.iter().enumerate() {
+            let checked = match inst.parts().0 {
+                "measure" => self.qubits(inst, limit).and_then(|qubits| match qubits.len() {
+                    1 => Ok(()),
+                    n => Err(format!("'measure' does not act on {} qubits", n)),
+                }),
+                _ => self.operands_within(inst, limit).map(|_| ()),
+            };
+            checked.map_err(|e| format!("line {}: {}", i + 1, e))?;
+        }
+        Ok(())
+    }
+    fn is_clifford(&self, program: &[Instruction]) -> bool {
+        // builtin clifford gates, not redefined, and measurements
+        let standard = standard_gates();
+        program.iter().all(|inst| {
+            let (expr, words) = inst.parts();
+            match stabilizer::arity(expr) {
+                _ if expr == "measure" => true,
+                Some(n) => n == words.len() && self.gates.get(expr) == standard.get(expr),
+                None => false,
             }
-            Err(_) => false,
+        })
+    }
+    // whether the program is a plain unitary on the dense state, without
+    // measurements, unknown gates or qubits past NQ
+    pub fn is_unitary(&self, program: &[Instruction]) -> bool {
+        program
+            .iter()
+            .all(|inst| inst.parts().0 != "measure" && self.operands(inst).is_ok())
+    }
+    // whether the program is past the dense state, which then stays at |0>
+    pub fn is_wide(&self) -> bool {
+        self.width > NQ
+    }
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn set_gates(&mut self, gates: &str) -> Result<(), String> {
+        // replace every custom gate, builtins always stay
+        let mut table = standard_gates();
+        table.extend(parse_gates(gates)?);
+        self.replace_gates(table)
+    }
+    pub fn merge_gates(&mut self, gates: &str) -> Result<(), String> {
+        // add or override the named gates, leaving the rest untouched
+        let mut table = self.gates.clone();
+        table.extend(parse_gates(gates)?);
+        self.replace_gates(table)
+    }
+    pub fn remove_gate(&mut self, name: &str) -> Result<(), String> {
+        if standard_gates().contains_key(name) {
+            return Err(format!("gate '{}' is builtin and cannot be removed", name));
         }
+        let mut table = self.gates.clone();
+        match table.remove(name) {
+            Some(_) => self.replace_gates(table),
+            None => Err(format!("no gate named '{}'", name)),
+        }
+    }
+    pub fn restore_gates(&mut self) -> Result<(), String> {
+        self.replace_gates(standard_gates())
+    }
+    // Swaps in a new gate table if the loaded program still runs with it,
+    // and otherwise keeps the old one.
+    fn replace_gates(&mut self, gates: BTreeMap<String, Gate>) -> Result<(), String> {
+        let old = ::std::mem::replace(&mut self.gates, gates);
+        if let Err(e) = self.check(&self.program) {
+            self.gates = old;
+            return Err(e);
+        }
+        // outcomes drawn under the old gates are drawn again
+        self.outcomes = vec![None; self.program.len()];
+        self.backend = self.build_backend();
+        self.replay();
+        Ok(())
     }
     pub fn show_gates(&self) -> String {
+        gatefmt::fmt_gates(&self.gates)
+    }
+    pub fn show_gates_json(&self) -> String {
         serde_json::to_string_pretty(&self.gates).unwrap()
     }
-    fn operate(&mut self) {
-        if let Instruction::Single(gate, qb) = &self.program[self.counter] {
-            let gate = &self.gates[gate];
-            let qb = usize::from_str_radix(&qb, 10).unwrap();
-            let lifted = lift_gate(qb, gate);
-            self.state = dot_product(&lifted, &self.state);
-        } else if let Instruction::Double(gate, qb0, qb1) = &self.program[self.counter] {
-            let swap = &self.gates["swap"];
-            let swappers: Vec<Gate> = (0..NQ-1).map(|n| lift_gate(n,swap)).collect();
-
-            let qb0 = usize::from_str_radix(&qb0, 10).unwrap();
-            let qb1 = usize::from_str_radix(&qb1, 10).unwrap();
-            let low = min(qb0, qb1);
-            let high = max(qb0, qb1);
-
-            let gate = &lift_gate(low, &self.gates[gate]);
-            let mut gatelist = Vec::new();
-            for i in 0..(high-low) {
-                gatelist.push(&swappers[high-1-i]);
+    pub fn resolve(&self, expr: &str) -> Result<Gate, String> {
+        // modifiers apply right to left, innermost first
+        let expr = expr.replace('@', " @ ");
+        let words: Vec<&str> = expr.split_whitespace().filter(|&w| w != "@").collect();
+        let (name, modifiers) = match words.split_last() {
+            Some(split) => split,
+            None => return Err("missing gate".into()),
+        };
+        let mut gate = match self.gates.get(*name) {
+            Some(gate) => gate.clone(),
+            None => rotation(name)?,
+        };
+        for modifier in modifiers.iter().rev() {
+            gate = match *modifier {
+                "dagger" | "inv" => linalg::adjoint(&gate),
+                "ctrl" => controlled(&gate),
+                word if word.starts_with("pow(") && word.ends_with(')') => {
+                    let k: i32 = word[4..word.len() - 1]
+                        .parse()
+                        .map_err(|_| format!("pow needs an integer, got '{}'", word))?;
+                    let one = linalg::identity(gate.len());
+                    power(gate, k, one, &linalg::adjoint, &mul)
+                }
+                word => return Err(format!("unknown modifier '{}'", word)),
+            };
+        }
+        Ok(gate)
+    }
+    pub fn inspect(&self, expr: &str) -> Result<Inspection, String> {
+        Ok(inspect::inspect(&self.resolve(expr)?))
+    }
+    pub fn transpile(&self, basis: &str) -> Result<Vec<Instruction>, String> {
+        // every instruction outside the basis is lowered, and each rewrite is
+        // checked against the gate it replaces before moving on
+        let basis = transpile::parse_basis(basis, &self.gates)?;
+        let mut ret = Vec::new();
+        for (i, inst) in self.program.iter().enumerate() {
+            let (expr, _) = inst.parts();
+            if basis.contains(expr) || expr == "measure" {
+                ret.push(inst.clone());
+                continue;
+            }
+            let (gate, qubits) = self.operands(inst)?;
+            let lines = transpile::lower(&gate, &qubits, &basis).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            let text: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+            let fragment = parse_program(&text).ok_or(format!("line {}: malformed rewrite", i + 1))?;
+            self.check(&fragment)?;
+            if linalg::phase_between(&gate, &self.unitary_on(&fragment, &qubits), UNITARY_TOL).is_none() {
+                return Err(format!("line {}: rewrite of '{}' is not equivalent", i + 1, expr));
+            }
+            ret.extend(fragment);
+        }
+        Ok(ret)
+    }
+    pub fn optimize(&self) -> Result<(Vec<Instruction>, Vec<String>), String> {
+        // the optimised program and what was removed, checked against the original
+        if !self.is_unitary(&self.program) {
+            return Err("only programs without measurements on the first 8 qubits can be optimised".into());
+        }
+        let mut ops = Vec::new();
+        for (i, inst) in self.program.iter().enumerate() {
+            let (gate, qubits) = self.operands(inst).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            ops.push(Op {
+                inst: inst.clone(),
+                gate,
+                qubits,
+                line: i + 1,
+            });
+        }
+        let (ops, removed) = optimize::peephole(ops);
+        let program: Vec<Instruction> = ops.into_iter().map(|op| op.inst).collect();
+        match self.compare(&program)? {
+            Equivalence::Same { .. } => Ok((program, removed)),
+            Equivalence::Differs { input, .. } => {
+                Err(format!("optimised program differs on input |{:08b}>", input))
+            }
+        }
+    }
+    pub fn approximate(&self, expr: &str, accuracy: Float) -> Result<Approximation, String> {
+        // h, s and t only, for checking fault tolerant compilations
+        let gate = self.resolve(expr)?;
+        if gate.len() != 2 {
+            return Err(format!("'{}' is not a single qubit gate", expr));
+        }
+        if !(accuracy > 0.0) {
+            return Err(format!("accuracy must be positive, got {}", accuracy));
+        }
+        let mut net = self.net.borrow_mut();
+        net.get_or_insert_with(Net::new).approximate(&gate, accuracy)
+    }
+    pub fn synthesize(&self, expr: &str, a: usize, b: usize) -> Result<Vec<Instruction>, String> {
+        // three cnots and single qubit rotations, checked against the gate itself
+        let gate = self.resolve(expr)?;
+        if gate.len() != 4 {
+            return Err(format!("'{}' is not a two qubit gate", expr));
+        }
+        if a >= NQ || b >= NQ || a == b {
+            return Err(format!("bad qubits {} {}", a, b));
+        }
+        let fragment = parse_program(&kak::synthesize(&gate, &a.to_string(), &b.to_string()))
+            .ok_or("synthesized a malformed program")?;
+        self.check(&fragment)?;
+        let found = self.unitary_on(&fragment, &[a, b]);
+        match linalg::phase_between(&gate, &found, UNITARY_TOL) {
+            Some(_) => Ok(fragment),
+            None => Err(format!("synthesis of '{}' did not verify", expr)),
+        }
+    }
+    fn operands(&self, inst: &Instruction) -> Result<(Gate, Vec<usize>), String> {
+        // qubits[0] is the most significant bit of the gate, eg. the control of cnot
+        self.operands_within(inst, NQ)
+    }
+    fn operands_within(&self, inst: &Instruction, limit: usize) -> Result<(Gate, Vec<usize>), String> {
+        let (expr, words) = inst.parts();
+        let gate = self.resolve(expr)?;
+        let qubits = self.qubits(inst, limit)?;
+        if gate.len() != 1 << qubits.len() {
+            return Err(format!("'{}' does not act on {} qubits", expr, words.len()));
+        }
+        Ok((gate, qubits))
+    }
+    fn qubits(&self, inst: &Instruction, limit: usize) -> Result<Vec<usize>, String> {
+        let mut qubits = Vec::new();
+        for word in inst.parts().1 {
+            let qb = usize::from_str_radix(word, 10).map_err(|_| format!("bad qubit '{}'", word))?;
+            if qb >= limit || qubits.contains(&qb) {
+                return Err(format!("bad qubit '{}'", word));
+            }
+            qubits.push(qb);
+        }
+        Ok(qubits)
+    }
+    fn operate(&mut self, adjoint: bool) {
+        let inst = self.program[self.counter].clone();
+        if inst.parts().0 == "measure" {
+            let qubits = self.qubits(&inst, STABILIZER_NQ).expect("checked on update");
+            let outcome = self.measure(qubits[0], self.outcomes[self.counter]);
+            self.outcomes[self.counter] = Some(outcome);
+        } else {
+            let (gate, qubits) = self.operands_within(&inst, STABILIZER_NQ).expect("checked on update");
+            let gate = if adjoint { linalg::adjoint(&gate) } else { gate };
+            let before = self.backend.norm();
+            self.truncation[self.counter] = self.backend.apply(inst.parts().0, &gate, &qubits);
+            let after = self.backend.norm();
+            self.drift[self.counter] = if adjoint { 0.0 } else { after - before };
+            if self.renormalise && (after - 1.0).abs() > self.drift_tol {
+                self.backend.rescale(1.0 / after);
+            }
+        }
+    }
+    fn measure(&mut self, qb: usize, forced: Option<bool>) -> bool {
+        // a recorded outcome is replayed only while it can still happen
+        let roll = self.random();
+        let p = self.backend.probability(qb);
+        let outcome = match forced {
+            Some(outcome) if (if outcome { p } else { 1.0 - p }) > ZERO_TOL => outcome,
+            _ => roll < p,
+        };
+        self.backend.collapse(qb, outcome);
+        outcome
+    }
+    fn random(&mut self) -> Float {
+        // xorshift, seeded once so runs are repeatable
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        (self.seed >> 40) as Float / (1u64 << 24) as Float
+    }
+    fn replay(&mut self) {
+        // runs the first `counter` lines again from |0>, with the same outcomes
+        let end = self.counter;
+        self.counter = 0;
+        self.restart();
+        while self.counter < end {
+            self.operate(false);
+            self.counter += 1;
+        }
+        self.refresh();
+    }
+    pub fn unitary(&self) -> Result<Matrix, String> {
+        self.unitary_range(0, self.program.len())
+    }
+    pub fn unitary_range(&self, start: usize, end: usize) -> Result<Matrix, String> {
+        Ok(product(&self.resolved(self.lines(start, end)?, start)?))
+    }
+    fn lines(&self, start: usize, end: usize) -> Result<&[Instruction], String> {
+        if start <= end && end <= self.program.len() {
+            Ok(&self.program[start..end])
+        } else {
+            Err(format!("no lines {} to {} in a program of {}", start + 1, end, self.program.len()))
+        }
+    }
+    // Each line's gate and operands on the dense state, for a program whose
+    // first line is line `first` + 1.
+    fn resolved(&self, program: &[Instruction], first: usize) -> Result<Vec<(Gate, Vec<usize>)>, String> {
+        program
+            .iter()
+            .enumerate()
+            .map(|(i, inst)| {
+                if inst.parts().0 == "measure" {
+                    return Err(format!("line {}: a measurement has no unitary", first + i + 1));
+                }
+                self.operands(inst).map_err(|e| format!("line {}: {}", first + i + 1, e))
+            })
+            .collect()
+    }
+    fn unitary_on(&self, program: &[Instruction], qubits: &[usize]) -> Matrix {
+        // like restrict(unitary(..)) for a program touching only `qubits`,
+        // simulated on those alone
+        let k = qubits.len();
+        let local = |qb: &usize| k - 1 - qubits.iter().position(|q| q == qb).expect("qubit in range");
+        let ops: Vec<(Gate, Vec<usize>)> = self
+            .resolved(program, 0)
+            .expect("checked program")
+            .into_iter()
+            .map(|(gate, operands)| (gate, operands.iter().map(&local).collect()))
+            .collect();
+        let mut columns = Vec::new();
+        for j in 0..1 << k {
+            let mut col = vec![C0; 1 << k];
+            col[j] = C1;
+            for &(ref gate, ref operands) in &ops {
+                col = apply_gate(gate, operands, &col);
+            }
+            columns.push(col);
+        }
+        (0..1 << k).map(|i| columns.iter().map(|col| col[i]).collect()).collect()
+    }
+    pub fn inverse(&self) -> Result<Vec<Instruction>, String> {
+        self.inverse_range(0, self.program.len())
+    }
+    pub fn inverse_range(&self, start: usize, end: usize) -> Result<Vec<Instruction>, String> {
+        // reversed, with every gate swapped for its adjoint
+        let lines = self.lines(start, end)?;
+        let ops = self.resolved(lines, start)?;
+        Ok(lines
+            .iter()
+            .zip(ops)
+            .rev()
+            .map(|(inst, (gate, _))| {
+                let (expr, qubits) = inst.parts();
+                let adjoint = if gate == linalg::adjoint(&gate) {
+                    expr.to_string()
+                } else if expr.starts_with("dagger ") {
+                    expr["dagger ".len()..].to_string()
+                } else if expr.starts_with("inv @ ") {
+                    expr["inv @ ".len()..].to_string()
+                } else {
+                    format!("dagger {}", expr)
+                };
+                let qubits: Vec<String> = qubits.iter().map(|qb| qb.to_string()).collect();
+                parse_instruction(&format!("{} {}", adjoint, qubits.join(" ")))
+            })
+            .collect())
+    }
+    pub fn compare(&self, other: &[Instruction]) -> Result<Equivalence, String> {
+        // other == phase * self, with the phase read off the largest entry
+        let u = self.unitary()?;
+        let v = product(&self.resolved(other, 0)?);
+        let mut pivot = (0, 0);
+        for i in 0..S {
+            for j in 0..S {
+                if u[i][j].norm() > u[pivot.0][pivot.1].norm() {
+                    pivot = (i, j);
+                }
+            }
+        }
+        let phase = v[pivot.0][pivot.1] / u[pivot.0][pivot.1];
+        for j in 0..S {
+            let differs = (0..S).any(|i| (v[i][j] - phase * u[i][j]).norm() > TOL);
+            if differs || (phase.norm() - 1.0).abs() > TOL {
+                let amplitudes = (0..S)
+                    .filter(|&i| !is_zero(u[i][j]) || !is_zero(v[i][j]))
+                    .map(|i| (i, u[i][j], v[i][j]))
+                    .collect();
+                return Ok(Equivalence::Differs { input: j, amplitudes });
             }
-            gatelist.push(gate);
-            self.state = dot_product(&join_gates(gatelist), &self.state);
         }
+        Ok(Equivalence::Same { phase })
+    }
+    // Resolves `expr` exactly, for builtin clifford+t gates that have not
+    // been redefined, under any of the modifiers resolve takes.
+    pub fn resolve_exact(&self, expr: &str) -> Result<ExactMatrix, String> {
+        let expr = expr.replace('@', " @ ");
+        let words: Vec<&str> = expr.split_whitespace().filter(|&w| w != "@").collect();
+        let (name, modifiers) = match words.split_last() {
+            Some(split) => split,
+            None => return Err("missing gate".into()),
+        };
+        let mut gate = match exact::gate(name) {
+            Some(ref gate) if self.gates.get(*name) == standard_gates().get(*name) => gate.clone(),
+            _ => return Err(format!("'{}' has no exact form", name)),
+        };
+        for modifier in modifiers.iter().rev() {
+            gate = match *modifier {
+                "dagger" | "inv" => exact::adjoint(&gate),
+                "ctrl" => exact::controlled(&gate),
+                word if word.starts_with("pow(") && word.ends_with(')') => {
+                    let k: i32 = word[4..word.len() - 1]
+                        .parse()
+                        .map_err(|_| format!("pow needs an integer, got '{}'", word))?;
+                    let one = exact::identity(gate.len());
+                    power(gate, k, one, &exact::adjoint, &exact::mul)
+                }
+                word => return Err(format!("unknown modifier '{}'", word)),
+            };
+        }
+        Ok(gate)
+    }
+    // Each line's exact gate and operands, resolved once for every column.
+    fn resolved_exact(&self, program: &[Instruction]) -> Result<Vec<(ExactMatrix, Vec<usize>)>, String> {
+        self.resolved(program, 0)?
+            .into_iter()
+            .zip(program)
+            .enumerate()
+            .map(|(i, ((_, qubits), inst))| {
+                let gate = self.resolve_exact(inst.parts().0).map_err(|e| format!("line {}: {}", i + 1, e))?;
+                Ok((gate, qubits))
+            })
+            .collect()
+    }
+    // the amplitudes after the lines run so far, in Z[1/√2, i]
+    pub fn exact_state(&self) -> Result<Vec<Exact>, String> {
+        Ok(exact_run(&self.resolved_exact(&self.program[..self.counter])?, 0))
+    }
+    // like compare, but exact, so Same only for the very same unitary up to phase
+    pub fn compare_exact(&self, other: &[Instruction]) -> Result<Equivalence, String> {
+        let (ours, theirs) = (self.resolved_exact(&self.program)?, self.resolved_exact(other)?);
+        let u: Vec<Vec<Exact>> = (0..S).map(|j| exact_run(&ours, j)).collect();
+        let v: Vec<Vec<Exact>> = (0..S).map(|j| exact_run(&theirs, j)).collect();
+        // columns here, so u[j][i] is row i of column j
+        let pivot = u[0].iter().position(|c| !c.is_zero()).expect("a unitary column is not zero");
+        let (up, vp) = (u[0][pivot], v[0][pivot]);
+        let same_size = up * up.conj() == vp * vp.conj();
+        for j in 0..S {
+            let differs = (0..S).any(|i| v[j][i] * up != u[j][i] * vp);
+            if differs || !same_size {
+                let amplitudes = (0..S)
+                    .filter(|&i| !u[j][i].is_zero() || !v[j][i].is_zero())
+                    .map(|i| (i, u[j][i].to_complex(), v[j][i].to_complex()))
+                    .collect();
+                return Ok(Equivalence::Differs { input: j, amplitudes });
+            }
+        }
+        Ok(Equivalence::Same {
+            phase: vp.to_complex() / up.to_complex(),
+        })
+    }
+    pub fn active_qubits(&self, start: usize, end: usize) -> Vec<usize> {
+        let mut ret: Vec<usize> = self.program[start..end]
+            .iter()
+            .flat_map(|inst| self.operands(inst).map(|(_, qubits)| qubits).unwrap_or_default())
+            .collect();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+    // the analyses below read the dense view, which stays at |0> past NQ qubits
+    fn dense(&self, what: &str) -> Result<(), String> {
+        if self.is_wide() {
+            Err(format!("{} needs a program on up to 8 qubits", what))
+        } else {
+            Ok(())
+        }
+    }
+    pub fn expectation(&self, observable: &str) -> Result<Float, String> {
+        self.expectation_of(&Observable::parse(observable)?)
+    }
+    pub fn expectation_of(&self, observable: &Observable) -> Result<Float, String> {
+        self.dense("an expectation value")?;
+        Ok(observable.expectation(&self.state))
+    }
+    pub fn bloch_vectors(&self) -> Result<Vec<Bloch>, String> {
+        // one for each qubit the program uses, not every qubit of the dense state
+        self.dense("a bloch vector")?;
+        Ok((0..self.width).map(|qb| density::bloch(&self.state, qb)).collect())
+    }
+    pub fn entanglement_entropy(&self, part: &[usize]) -> Result<Float, String> {
+        self.dense("entanglement entropy")?;
+        // each qubit of the dense state once
+        for (i, &qb) in part.iter().enumerate() {
+            if qb >= NQ || part[..i].contains(&qb) {
+                return Err(format!("bad qubit '{}'", qb));
+            }
+        }
+        Ok(density::entanglement_entropy(&self.state, part))
+    }
+    pub fn entanglement_links(&self) -> Result<Vec<Link>, String> {
+        self.dense("entanglement links")?;
+        let mut links = self.links.borrow_mut();
+        Ok(links.get_or_insert_with(|| density::links(&self.state)).clone())
     }
     pub fn prev(&mut self) {
+        // neither a measurement, a truncation nor a renormalisation can be
+        // undone, nor a gate whose adjoint is not quite its inverse, so
+        // everything before them is run again
         if self.counter > 0 {
             self.counter -= 1;
-            self.operate();
+            self.triggered = vec![];
+            let line = self.program[self.counter].clone();
+            let undoable = line.parts().0 != "measure"
+                && self.backend.reversible()
+                && !self.renormalise
+                && self
+                    .operands_within(&line, STABILIZER_NQ)
+                    .map_or(false, |(gate, _)| unitary_deviation(&gate) <= ZERO_TOL);
+            if !undoable {
+                self.replay();
+            } else {
+                self.operate(true);
+                self.refresh();
+            }
         }
     }
     pub fn next(&mut self) {
         if self.counter < self.program.len() {
-            self.operate();
+            let conditions = self.conditions.clone();
+            let before: Vec<Option<Complex>> = conditions.iter().map(|c| self.quantity(&c.quantity).ok()).collect();
+            self.operate(false);
             self.counter += 1;
+            self.refresh();
+            // a quantity that cannot be read, eg. past a narrower program, never triggers
+            self.triggered = (0..conditions.len())
+                .filter(|&i| match (before[i], self.quantity(&conditions[i].quantity)) {
+                    (Some(before), Ok(after)) => conditions[i].triggered(before, after),
+                    _ => false,
+                })
+                .collect();
         }
     }
+    pub fn quantity(&mut self, quantity: &Quantity) -> Result<Complex, String> {
+        let width = self.width;
+        let within = |qb: usize| if qb < width { Ok(qb) } else { Err(format!("bad qubit '{}'", qb)) };
+        let real = |x: Float| Ok(Complex::new(x, 0.0));
+        match *quantity {
+            Quantity::Probability(ref qubits, bits) if qubits.len() == 1 => {
+                let p = self.backend.probability(within(qubits[0])?);
+                real(if bits == 1 { p } else { 1.0 - p })
+            }
+            Quantity::Probability(ref qubits, bits) => {
+                for (i, &qb) in qubits.iter().enumerate() {
+                    if within(qb).is_err() || qubits[..i].contains(&qb) {
+                        return Err(format!("bad qubit '{}'", qb));
+                    }
+                }
+                let target = deposit(bits, qubits);
+                let mask = deposit((1 << qubits.len()) - 1, qubits);
+                let probabilities = self
+                    .backend
+                    .probabilities()
+                    .ok_or(format!("the {} backend cannot list its probabilities", self.backend.name()))?;
+                real(probabilities.iter().filter(|&&(n, _)| n & mask == target).map(|&(_, p)| p).sum())
+            }
+            Quantity::Amplitude(n) => self
+                .backend
+                .amplitude(n)
+                .ok_or(format!("no amplitude for {} on the {} backend", n, self.backend.name())),
+            Quantity::Phase(n) => match self.backend.amplitude(n) {
+                Some(c) if !is_zero(c) => real(c.arg()),
+                Some(_) => Err("a zero amplitude has no phase".into()),
+                None => Err(format!("no amplitude for {} on the {} backend", n, self.backend.name())),
+            },
+            Quantity::Expectation(ref observable) => {
+                real(self.expectation(observable)?)
+            }
+            Quantity::Fidelity(ref name) => {
+                let mut overlap = C0;
+                for (n, c) in named_state(name, self.width)? {
+                    let amplitude = self.backend.amplitude(n).ok_or(format!("no amplitude for {}", n))?;
+                    overlap += c.conj() * amplitude;
+                }
+                real(overlap.norm_sqr())
+            }
+            Quantity::Concurrence(a, b) => {
+                self.dense("concurrence")?;
+                let (a, b) = (within(a)?, within(b)?);
+                if a == b {
+                    return Err(format!("bad qubit '{}'", b));
+                }
+                let rho = density::reduced_density_matrix(&self.state, &[a, b]);
+                real(density::concurrence(&rho))
+            }
+            Quantity::Norm => real(self.backend.norm()),
+            Quantity::Drift => real((self.backend.norm() - 1.0).abs()),
+        }
+    }
+    // Replaces the conditions with those on each line of `text`, if every
+    // one can be read on the current program.
+    pub fn set_conditions(&mut self, text: &str) -> Result<(), String> {
+        let mut conditions = Vec::new();
+        for (i, line) in text.lines().enumerate().filter(|&(_, line)| !line.trim().is_empty()) {
+            let condition = Condition::parse(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            self.quantity(&condition.quantity).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            conditions.push(condition);
+        }
+        self.conditions = conditions;
+        self.triggered = vec![];
+        Ok(())
+    }
+    pub fn conditions(&self) -> &[Condition] {
+        &self.conditions
+    }
+    // Replaces the watches with the quantities on each line of `text`. One
+    // that cannot be read on this program shows its error until it can.
+    pub fn set_watches(&mut self, text: &str) -> Result<(), String> {
+        let mut watches = Vec::new();
+        for (i, line) in text.lines().enumerate().filter(|&(_, line)| !line.trim().is_empty()) {
+            let quantity = Quantity::parse(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            watches.push((line.trim().to_string(), quantity));
+        }
+        self.watches = watches;
+        self.refresh();
+        Ok(())
+    }
+    pub fn watches(&self) -> &[(String, Quantity)] {
+        &self.watches
+    }
+    pub fn set_breakpoint(&mut self, line: usize) -> Result<(), String> {
+        if line >= self.program.len() {
+            return Err(format!("no line {} to break on", line + 1));
+        }
+        self.breakpoints.insert(line);
+        Ok(())
+    }
+    pub fn clear_breakpoint(&mut self, line: usize) {
+        self.breakpoints.remove(&line);
+    }
+    pub fn toggle_breakpoint(&mut self, line: usize) -> Result<(), String> {
+        if self.breakpoints.contains(&line) {
+            self.clear_breakpoint(line);
+            Ok(())
+        } else {
+            self.set_breakpoint(line)
+        }
+    }
+    pub fn breakpoints(&self) -> &BTreeSet<usize> {
+        &self.breakpoints
+    }
+    // Runs lines until the next one with a breakpoint, a step on which a
+    // condition became true, or the end, and returns whether it stopped early.
+    pub fn resume(&mut self) -> bool {
+        while self.counter < self.program.len() {
+            self.next();
+            if self.breakpoints.contains(&self.counter) || !self.triggered.is_empty() {
+                return true;
+            }
+        }
+        false
+    }
+}
+pub fn restrict(unitary: &Matrix, qubits: &[usize]) -> Matrix {
+    // the block acting on `qubits` with every other qubit held at |0>,
+    // which is all of it when nothing else is touched
+    let dim = 1 << qubits.len();
+    let offsets: Vec<usize> = (0..dim).map(|local| deposit(local, qubits)).collect();
+    offsets
+        .iter()
+        .map(|&r| offsets.iter().map(|&c| unitary[r][c]).collect())
+        .collect()
 }
 pub fn fmt_tensor(value: Complex, n: usize) -> (String, String) {
     if is_zero(value) {
@@ -303,6 +1298,8 @@ pub fn fmt_tensor(value: Complex, n: usize) -> (String, String) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use float::consts::FRAC_PI_2;
+    use linalg;
     use test::Bencher;
 
 
@@ -331,7 +1328,7 @@ mod tests {
             }
         }
     }
-    fn check_qubit(qvm: &QVM, bit: &str, n: f32) {
+    fn check_qubit(qvm: &QVM, bit: &str, n: Float) {
         assert!(eq(qvm.state[usize::from_str_radix(bit, 2).unwrap()].re, n)); // 00000
     }
     #[test]
@@ -340,7 +1337,7 @@ mod tests {
 cnot 0 1
 ".into();
         let qvm = run_test(prog);
-        let n = 1.0 / 2.0_f32.sqrt();
+        let n = 1.0 / (2.0 as Float).sqrt();
         check_qubit(&qvm, "0", n);
         check_qubit(&qvm, "11", n);
     }
@@ -389,4 +1386,347 @@ swap 2 7
         let qvm = run_test(prog);
         check_qubit(&qvm, "11100000", 1.0);
     }
+
+    #[test]
+    fn unitary_matches_state() {
+        let prog = "x 0
+cnot 0 1
+swap 1 2
+h 3
+cnot 2 3
+swap 7 0
+cnot 0 7
+".into();
+        let qvm = run_test(prog);
+        let u = qvm.unitary().unwrap();
+        for i in 0..S {
+            assert!((u[i][0] - qvm.state[i]).norm() < 1e-5);
+        }
+        let uu = mul(&linalg::adjoint(&u), &u);
+        for i in 0..S {
+            for j in 0..S {
+                let id = if i == j { C1 } else { C0 };
+                assert!((uu[i][j] - id).norm() < 1e-5);
+            }
+        }
+    }
+    #[test]
+    fn unitary_range() {
+        let prog = "x 5
+h 0
+cnot 0 1
+".into();
+        let qvm = run_test(prog);
+        assert_eq!(qvm.active_qubits(1, 3), vec![0, 1]);
+        let bell = restrict(&qvm.unitary_range(1, 3).unwrap(), &[1, 0]);
+        let n = 1.0 / (2.0 as Float).sqrt();
+        assert!(eq(bell[0][0].re, n) && eq(bell[3][0].re, n));
+        assert!(eq(bell[0][1].re, n) && eq(bell[3][1].re, -n));
+        assert!(eq(bell[2][2].re, n) && eq(bell[1][2].re, n));
+        assert!(eq(bell[2][3].re, n) && eq(bell[1][3].re, -n));
+        assert!(qvm.unitary_range(2, 4).is_err());
+        assert!(qvm.unitary_range(2, 1).is_err());
+        // loaded fine, but with no unitary on the dense state
+        for prog in &["h 0\ncnot 0 9", "h 0\nmeasure 0"] {
+            let qvm = run_test(prog.to_string());
+            assert!(qvm.unitary().unwrap_err().starts_with("line 2"));
+            assert!(qvm.compare(&[]).is_err());
+        }
+    }
+    #[test]
+    fn equivalence() {
+        let qvm = run_test("h 0\ncnot 0 1\nz 1\n".into());
+        let same = parse_program("h 0\nz 0\nz 1\ncnot 0 1").unwrap();
+        match qvm.compare(&same).unwrap() {
+            Equivalence::Same { phase } => assert!((phase - C1).norm() < TOL),
+            _ => panic!("cnot maps z0 z1 to z1"),
+        }
+        let phased = parse_program("x 0\nz 0\nx 0\nz 0\nh 0\ncnot 0 1\nz 1").unwrap();
+        match qvm.compare(&phased).unwrap() {
+            Equivalence::Same { phase } => assert!((phase + C1).norm() < TOL),
+            _ => panic!("xzxz is -1, a global phase"),
+        }
+        let other = parse_program("h 0\ncnot 0 1").unwrap();
+        match qvm.compare(&other).unwrap() {
+            Equivalence::Differs { input, amplitudes } => {
+                assert_eq!(input, 0);
+                assert_eq!(amplitudes.len(), 2);
+                assert!((amplitudes[1].1 + amplitudes[1].2).norm() < TOL);
+            }
+            _ => panic!("missing z"),
+        }
+    }
+    #[test]
+    fn gate_validation() {
+        let mut qvm = QVM::new();
+        let good = qvm.show_gates();
+        assert!(qvm.set_gates(&good).is_ok());
+        let ragged = r#"{"bad": [[[1, 0], [0, 0]], [[0, 0]]]}"#;
+        assert!(qvm.set_gates(ragged).unwrap_err().contains("row 1"));
+        let three = r#"{"bad": [[[1, 0], [0, 0], [0, 0]], [[0, 0], [1, 0], [0, 0]], [[0, 0], [0, 0], [1, 0]]]}"#;
+        assert!(qvm.set_gates(three).unwrap_err().contains("2^k"));
+        let scaled = r#"{"h": [[[0.707, 0], [0.707, 0]], [[0.707, 0], [-0.707, 0]]], "bad": [[[2, 0], [0, 0]], [[0, 0], [1, 0]]]}"#;
+        let err = qvm.set_gates(scaled).unwrap_err();
+        assert!(err.contains("'bad'") && err.contains("3.00e0"));
+        // a failed load leaves the old gates in place
+        assert_eq!(qvm.show_gates(), good);
+        assert!(qvm.set_gates(&qvm.show_gates_json()).is_ok());
+        assert!(qvm.set_gates("h:\n0.707, 0.707\n0.707, -0.707").unwrap_err().contains("builtin"));
+    }
+    #[test]
+    fn gate_merging() {
+        let mut qvm = QVM::new();
+        let flip = r#"{"flip": [[[0, 0], [1, 0]], [[1, 0], [0, 0]]]}"#;
+        qvm.merge_gates(flip).unwrap();
+        let qvm_gates = qvm.show_gates();
+        assert!(qvm_gates.contains("flip") && qvm_gates.contains("cnot"));
+        let phase = r#"{"flip": [[[1, 0], [0, 0]], [[0, 0], [0, 1]]], "other": [[[1, 0], [0, 0]], [[0, 0], [1, 0]]]}"#;
+        qvm.merge_gates(phase).unwrap();
+        assert!(qvm.show_gates().contains("other"));
+
+        let h = r#"{"h": [[[1, 0], [0, 0]], [[0, 0], [1, 0]]]}"#;
+        assert!(qvm.merge_gates(h).unwrap_err().contains("builtin"));
+        assert!(qvm.remove_gate("swap").is_err());
+        assert!(qvm.remove_gate("nope").is_err());
+        qvm.remove_gate("flip").unwrap();
+        assert!(!qvm.show_gates().contains("flip"));
+
+        qvm.set_gates(flip).unwrap();
+        assert!(!qvm.show_gates().contains("other"));
+        assert!(qvm.show_gates().contains("swap"));
+        qvm.restore_gates().unwrap();
+        assert_eq!(qvm.show_gates(), QVM::new().show_gates());
+    }
+    #[test]
+    fn gates_in_use() {
+        let mut qvm = QVM::new();
+        let flip = "foo:\n0, 1\n1, 0";
+        qvm.merge_gates(flip).unwrap();
+        assert!(qvm.update("foo 0"));
+        // the loaded program still needs foo, so every change that drops
+        // it is refused and leaves the table as it was
+        assert!(qvm.remove_gate("foo").unwrap_err().contains("foo"));
+        assert!(qvm.restore_gates().is_err());
+        assert!(qvm.set_gates("bar:\n0, 1\n1, 0").is_err());
+        let wide = "foo:\n1, 0, 0, 0\n0, 1, 0, 0\n0, 0, 0, 1\n0, 0, 1, 0";
+        assert!(qvm.merge_gates(wide).is_err());
+        assert!(qvm.show_gates().contains("foo") && !qvm.show_gates().contains("bar"));
+        qvm.next();
+        check_qubit(&qvm, "1", 1.0);
+        assert!(eq(qvm.unitary().unwrap()[0][1].re, 1.0));
+        assert!(!qvm.is_unitary(&parse_program("bar 0").unwrap()));
+        assert!(!qvm.is_unitary(&parse_program("foo 0 1").unwrap()));
+
+        // a redefinition of the same arity takes effect on the state so far
+        qvm.merge_gates("foo:\n1, 0\n0, 1").unwrap();
+        check_qubit(&qvm, "0", 1.0);
+        assert!(qvm.update("x 0"));
+        qvm.remove_gate("foo").unwrap();
+
+        // a redefinition that makes a recorded outcome impossible draws it again
+        qvm.merge_gates(flip).unwrap();
+        assert!(qvm.update("foo 0\nmeasure 0"));
+        qvm.next();
+        qvm.next();
+        assert_eq!(qvm.outcomes[1], Some(true));
+        qvm.merge_gates("foo:\n1, 0\n0, 1").unwrap();
+        assert_eq!(qvm.outcomes[1], Some(false));
+        assert!(eq(qvm.norm(), 1.0));
+        for backend in vec![Backend::Sparse, Backend::Mps(2)] {
+            qvm.select_backend(backend).unwrap();
+            assert!(eq(qvm.norm(), 1.0));
+        }
+    }
+    #[test]
+    fn modifiers() {
+        let toffoli = "x 0
+x 1
+ctrl @ ctrl @ x 0 1 2
+".into();
+        let qvm = run_test(toffoli);
+        check_qubit(&qvm, "111", 1.0);
+
+        // the first listed qubit is the control, whichever is lower, so
+        // cnot 1 0 is no longer read as cnot 0 1
+        let reversed = run_test("x 1\ncnot 1 0\n".into());
+        check_qubit(&reversed, "11", 1.0);
+        let forward = run_test("x 1\ncnot 0 1\n".into());
+        check_qubit(&forward, "10", 1.0);
+        let mut qvm = QVM::new();
+        assert!(qvm.update("cnot 1 0"));
+        match qvm.compare(&parse_program("cnot 0 1").unwrap()).unwrap() {
+            Equivalence::Differs { input, .. } => assert_eq!(input, 1),
+            _ => panic!("cnot 1 0 is cnot 0 1"),
+        }
+
+        let mut qvm = QVM::new();
+        let s = "s:\n1, 0\n0, i";
+        qvm.merge_gates(s).unwrap();
+        let zs = qvm.resolve("pow(2) @ s").unwrap();
+        assert!((zs[1][1] + C1).norm() < TOL);
+        let sdg = qvm.resolve("dagger s").unwrap();
+        assert!((sdg[1][1] + CI).norm() < TOL);
+        assert_eq!(qvm.resolve("inv @ pow(-1) @ s").unwrap(), qvm.resolve("s").unwrap());
+        assert!(qvm.resolve("ctrl @ q").is_err());
+        assert!(qvm.resolve("pow(0.5) @ s").is_err());
+        // squared up rather than multiplied out, down to i32::MIN
+        let s7 = qvm.resolve("pow(7) @ s").unwrap();
+        assert!((s7[1][1] + CI).norm() < TOL);
+        let one = qvm.resolve("pow(-2147483648) @ s").unwrap();
+        assert!((0..2).all(|i| (0..2).all(|j| (one[i][j] - if i == j { C1 } else { C0 }).norm() < TOL)));
+        assert_eq!(qvm.resolve_exact("pow(-2147483648) @ t").unwrap(), exact::identity(2));
+        assert_eq!(qvm.resolve_exact("pow(2147483647) @ t").unwrap(), qvm.resolve_exact("dagger t").unwrap());
+        assert!(!qvm.update("ctrl @ x 0"));
+        assert!(!qvm.update("h 0 0"));
+
+        // stepping back undoes gates that are not their own inverse
+        assert!(qvm.update("h 0\ns 0\nctrl @ s 0 1"));
+        qvm.next();
+        let start = qvm.state.clone();
+        qvm.next();
+        qvm.next();
+        qvm.prev();
+        qvm.prev();
+        for i in 0..S {
+            assert!((qvm.state[i] - start[i]).norm() < TOL);
+        }
+    }
+    #[test]
+    fn inverse_program() {
+        let mut qvm = QVM::new();
+        qvm.merge_gates("s:\n1, 0\n0, i").unwrap();
+        assert!(qvm.update("h 0\nctrl @ s 0 1\ndagger s 1\npow(3) @ s 2\ncnot 2 0"));
+        let inverse = qvm.inverse().unwrap();
+        assert_eq!(fmt_program(&inverse), "cnot 2 0\ndagger pow(3) @ s 2\ns 1\ndagger ctrl @ s 0 1\nh 0\n");
+        let mut undo = qvm.program.clone();
+        undo.extend(inverse);
+        let mut roundtrip = QVM::new();
+        roundtrip.merge_gates("s:\n1, 0\n0, i").unwrap();
+        assert!(roundtrip.update(&fmt_program(&undo)));
+        match roundtrip.compare(&[]).unwrap() {
+            Equivalence::Same { phase } => assert!((phase - C1).norm() < TOL),
+            _ => panic!("program followed by its inverse is the identity"),
+        }
+        assert_eq!(fmt_program(&qvm.inverse_range(1, 3).unwrap()), "s 1\ndagger ctrl @ s 0 1\n");
+        assert!(qvm.inverse_range(3, 9).is_err());
+        assert!(qvm.inverse_range(3, 1).is_err());
+        assert!(qvm.update("h 0\nmeasure 0\nx 0"));
+        assert_eq!(qvm.inverse_range(0, 3).unwrap_err(), "line 2: a measurement has no unitary");
+        assert_eq!(fmt_program(&qvm.inverse_range(2, 3).unwrap()), "x 0\n");
+    }
+    #[test]
+    fn long_program_drift() {
+        // a few hundred rotations and their inverses come back to |0> within
+        // TOL, whichever float type the build uses
+        let mut prog = String::new();
+        for _ in 0..200 {
+            prog += "rx(0.1) 0\nctrl @ ry(0.3) 0 1\nh 2\nt 2\n";
+        }
+        let mut qvm = QVM::new();
+        assert!(qvm.update(&prog));
+        let inverse = qvm.inverse().unwrap();
+        let mut undo = qvm.program.clone();
+        undo.extend(inverse);
+        assert!(qvm.update(&fmt_program(&undo)));
+        while qvm.counter < qvm.program.len() {
+            qvm.next();
+        }
+        assert!((qvm.state[0] - C1).norm() < TOL);
+        for i in 1..S {
+            assert!(qvm.state[i].norm() < TOL);
+        }
+    }
+    #[test]
+    fn norm_drift() {
+        // slightly off unitary, but within what validate_gate lets through
+        let mut qvm = QVM::new();
+        qvm.merge_gates("g:\n1.0002, 0\n0, 1.0002\nk:\n1.0004, 0\n0, 1.0004").unwrap();
+        assert!(qvm.update("h 0\ng 0\nk 0\ncnot 0 1\ng 1\n"));
+        while qvm.counter < qvm.program.len() {
+            qvm.next();
+        }
+        assert!((qvm.norm() - 1.0008).abs() < 1e-5);
+        assert_eq!(qvm.worst_drift().map(|(i, _)| i), Some(2));
+        qvm.prev();
+        // undone by running up to it again, the adjoint would grow it further
+        assert!((qvm.norm() - 1.0006).abs() < 1e-5);
+        qvm.prev();
+        assert_eq!(qvm.worst_drift().map(|(i, _)| i), Some(2));
+        qvm.prev();
+        assert_eq!(qvm.worst_drift().map(|(i, _)| i), Some(1));
+        assert!(qvm.set_drift_tolerance(1e-3).is_ok());
+        assert_eq!(qvm.worst_drift(), None);
+        assert!(qvm.set_drift_tolerance(0.0).is_err());
+        // renormalising keeps the state at norm 1, and the report
+        qvm.set_drift_tolerance(TOL).unwrap();
+        qvm.set_renormalise(true);
+        while qvm.counter < qvm.program.len() {
+            qvm.next();
+        }
+        assert!((qvm.norm() - 1.0).abs() < 1e-5);
+        assert_eq!(qvm.worst_drift().map(|(i, _)| i), Some(2));
+        qvm.prev();
+        assert!((qvm.norm() - 1.0).abs() < 1e-5);
+    }
+    #[test]
+    fn mps_drift() {
+        // the same drift as on the dense state, with truncation kept apart
+        let mut qvm = QVM::new();
+        qvm.merge_gates("g:\n1.0002, 0\n0, 1.0002\nk:\n1.0004, 0\n0, 1.0004").unwrap();
+        qvm.select_backend(Backend::Mps(1)).unwrap();
+        assert!(qvm.update("h 0\ng 0\nk 0\ncnot 0 3\ng 3\n"));
+        while qvm.counter < qvm.program.len() {
+            qvm.next();
+        }
+        assert!((qvm.norm() - 1.0008).abs() < 1e-5);
+        assert_eq!(qvm.worst_drift().map(|(i, _)| i), Some(2));
+        assert!((qvm.truncation[3] - 0.5).abs() < 1e-4);
+        assert!(qvm.drift[3].abs() < 1e-5);
+        qvm.set_renormalise(true);
+        assert!((qvm.norm() - 1.0).abs() < 1e-5);
+    }
+    #[test]
+    fn breakpoints() {
+        let mut qvm = QVM::new();
+        assert!(qvm.update("h 0\nx 1\ncnot 0 1\nz 0\nh 1\n"));
+        qvm.set_breakpoint(2).unwrap();
+        qvm.toggle_breakpoint(4).unwrap();
+        assert!(qvm.set_breakpoint(5).is_err());
+        // stops before the line, and moves on past it when resumed again
+        assert!(qvm.resume());
+        assert_eq!(qvm.counter, 2);
+        qvm.toggle_breakpoint(4).unwrap();
+        assert!(!qvm.resume());
+        assert_eq!(qvm.counter, 5);
+        // breakpoints past the end of a shorter program are dropped
+        qvm.set_breakpoint(4).unwrap();
+        assert!(qvm.update("h 0\nx 1\ncnot 0 1\n"));
+        assert_eq!(qvm.breakpoints().iter().cloned().collect::<Vec<_>>(), vec![2]);
+        qvm.reset();
+        assert!(qvm.resume());
+        assert_eq!(qvm.counter, 2);
+    }
+    #[test]
+    fn watches() {
+        let mut qvm = QVM::new();
+        assert!(qvm.update("h 0\ncnot 0 1\ns 1\n"));
+        qvm.set_watches("P(0 1=11)\nE(Z0 Z1)\nphase(11)\nF(bell)\nE(X9)\n").unwrap();
+        assert!(qvm.set_watches("P(0 1)").is_err());
+        assert_eq!(qvm.watches().len(), 5);
+        let read = |qvm: &QVM| -> Vec<Float> { qvm.watched.iter().map(|w| w.as_ref().map(|c| c.re).unwrap_or(-9.0)).collect() };
+        let close = |a: &[Float], b: &[Float]| a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1e-4);
+        assert!(close(&read(&qvm), &[0.0, 1.0, -9.0, 0.5, -9.0]));
+        qvm.next();
+        qvm.next();
+        assert!(close(&read(&qvm), &[0.5, 1.0, 0.0, 1.0, -9.0]));
+        qvm.next();
+        assert!(close(&read(&qvm), &[0.5, 1.0, FRAC_PI_2, 0.5, -9.0]));
+        // a new program keeps them, read again from its start
+        assert!(qvm.update("x 0\nx 1\n"));
+        qvm.reset();
+        qvm.next();
+        qvm.next();
+        assert_eq!(qvm.watches().len(), 5);
+        assert!(close(&read(&qvm), &[1.0, 1.0, 0.0, 0.5, -9.0]));
+    }
 }
@@ -0,0 +1,223 @@
+// The interface every state backend gives the debugger, so stepping,
+// measurement and display are the same whichever one runs a program.
+use float::Float;
+use qvm::{apply_gate, is_zero, Complex, Matrix, Qstate, C0, C1, NQ};
+
+pub trait Simulator {
+    fn name(&self) -> &'static str;
+    fn width(&self) -> usize;
+    // Applies `gate`, named `expr`, on `qubits` (qubits[0] its most
+    // significant bit) and returns the fraction of the weight lost to truncation.
+    fn apply(&mut self, expr: &str, gate: &Matrix, qubits: &[usize]) -> Float;
+    // whether applying the adjoint undoes a gate, otherwise stepping back
+    // runs the program again from the start
+    fn reversible(&self) -> bool {
+        true
+    }
+    // the probability of reading 1 from `qb`
+    fn probability(&mut self, qb: usize) -> Float;
+    fn collapse(&mut self, qb: usize, outcome: bool);
+    fn reset(&mut self);
+    fn amplitude(&self, n: usize) -> Option<Complex>;
+    // the state's 2-norm, 1 up to rounding unless a gate was not quite unitary
+    fn norm(&self) -> Float {
+        self.probabilities().map_or(1.0, |p| p.iter().map(|&(_, p)| p).sum::<Float>().sqrt())
+    }
+    // multiplies every amplitude by `factor`, to bring a drifted state back to norm 1
+    fn rescale(&mut self, factor: Float);
+    // the non-zero amplitudes by basis index, or None when there are too many to list
+    fn amplitudes(&self) -> Option<Vec<(usize, Complex)>>;
+    fn probabilities(&self) -> Option<Vec<(usize, Float)>> {
+        self.amplitudes()
+            .map(|amplitudes| amplitudes.into_iter().map(|(n, c)| (n, c.norm_sqr())).collect())
+    }
+    // an independent copy, which QVM::restore puts back in place
+    fn snapshot(&self) -> Box<dyn Simulator>;
+    fn generators(&self) -> Option<Vec<String>> {
+        None
+    }
+    // a line about the backend's own state, eg. the bond dimension in use
+    fn status(&self) -> String {
+        String::new()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Backend {
+    // dense up to NQ qubits, then the tableau for clifford programs and the sparse state otherwise
+    Auto,
+    Dense,
+    Sparse,
+    Mps(usize),
+    Stabilizer,
+}
+
+// "auto", "dense", "sparse", "mps 16" or "stabilizer"
+pub fn parse_backend(text: &str) -> Result<Backend, String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    match (words.get(0).cloned(), words.len()) {
+        (Some("auto"), 1) | (None, 0) => Ok(Backend::Auto),
+        (Some("dense"), 1) => Ok(Backend::Dense),
+        (Some("sparse"), 1) => Ok(Backend::Sparse),
+        (Some("stabilizer"), 1) => Ok(Backend::Stabilizer),
+        (Some("mps"), 2) => match words[1].parse::<usize>() {
+            Ok(bond) if bond > 0 => Ok(Backend::Mps(bond)),
+            _ => Err(format!("bad bond dimension '{}'", words[1])),
+        },
+        _ => Err(format!("unknown backend '{}'", text.trim())),
+    }
+}
+
+// the full state vector on up to NQ qubits
+#[derive(Clone)]
+pub struct Dense {
+    width: usize,
+    state: Qstate,
+}
+
+impl Dense {
+    pub fn new(width: usize) -> Dense {
+        let mut state = vec![C0; 1 << NQ];
+        state[0] = C1;
+        Dense { width, state }
+    }
+}
+
+impl Simulator for Dense {
+    fn name(&self) -> &'static str {
+        "dense"
+    }
+    fn width(&self) -> usize {
+        self.width
+    }
+    fn apply(&mut self, _: &str, gate: &Matrix, qubits: &[usize]) -> Float {
+        self.state = apply_gate(gate, qubits, &self.state);
+        0.0
+    }
+    fn probability(&mut self, qb: usize) -> Float {
+        (0..self.state.len())
+            .filter(|n| n >> qb & 1 == 1)
+            .map(|n| self.state[n].norm_sqr())
+            .sum()
+    }
+    fn collapse(&mut self, qb: usize, outcome: bool) {
+        let bit = outcome as usize;
+        let kept: Float = (0..self.state.len())
+            .filter(|n| n >> qb & 1 == bit)
+            .map(|n| self.state[n].norm_sqr())
+            .sum();
+        if kept <= 0.0 {
+            return;
+        }
+        for (n, c) in self.state.iter_mut().enumerate() {
+            *c = if n >> qb & 1 == bit { *c / kept.sqrt() } else { C0 };
+        }
+    }
+    fn reset(&mut self) {
+        *self = Dense::new(self.width);
+    }
+    fn amplitude(&self, n: usize) -> Option<Complex> {
+        self.state.get(n).cloned()
+    }
+    fn norm(&self) -> Float {
+        self.state.iter().map(|c| c.norm_sqr()).sum::<Float>().sqrt()
+    }
+    fn rescale(&mut self, factor: Float) {
+        for c in self.state.iter_mut() {
+            *c = *c * factor;
+        }
+    }
+    fn amplitudes(&self) -> Option<Vec<(usize, Complex)>> {
+        Some(self.state.iter().cloned().enumerate().filter(|&(_, c)| !is_zero(c)).collect())
+    }
+    fn snapshot(&self) -> Box<dyn Simulator> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qvm::{QVM, TOL};
+    use sparse::SparseState;
+
+    #[test]
+    fn backends_agree() {
+        let prog = "h 0\ncnot 0 1\ns 1\nh 2\ncz 2 0\nswap 1 2\ny 0\nmeasure 1\n";
+        let mut states = Vec::new();
+        for backend in &["dense", "sparse", "mps 4", "stabilizer", "auto"] {
+            let mut qvm = QVM::new();
+            qvm.select_backend(parse_backend(backend).unwrap()).unwrap();
+            assert!(qvm.update(prog));
+            let name = match *backend {
+                "auto" => "dense",
+                "mps 4" => "mps",
+                other => other,
+            };
+            assert_eq!(qvm.backend().name(), name);
+            while qvm.counter < qvm.program.len() {
+                qvm.next();
+            }
+            states.push((qvm.outcomes[7], qvm.backend().probabilities().unwrap()));
+        }
+        for &(outcome, ref probabilities) in &states {
+            // the seeded outcome and so the collapsed state are the same everywhere
+            assert_eq!(outcome, states[0].0);
+            let mut found = probabilities.clone();
+            found.retain(|&(_, p)| p > 1e-6);
+            let expected: Vec<(usize, Float)> = states[0].1.iter().cloned().filter(|&(_, p)| p > 1e-6).collect();
+            assert_eq!(found.len(), expected.len());
+            for (a, b) in found.iter().zip(expected.iter()) {
+                assert_eq!(a.0, b.0);
+                assert!((a.1 - b.1).abs() < 1e-4);
+            }
+        }
+    }
+    #[test]
+    fn selection() {
+        assert_eq!(parse_backend(" mps 8 "), Ok(Backend::Mps(8)));
+        assert!(parse_backend("mps 0").is_err());
+        assert!(parse_backend("gpu").is_err());
+        let mut qvm = QVM::new();
+        assert!(qvm.update("h 0\nt 0\n"));
+        // t is not clifford, and the program stays on the old backend
+        assert!(qvm.select_backend(Backend::Stabilizer).is_err());
+        assert_eq!(qvm.backend().name(), "dense");
+        assert!(qvm.update("h 12\n"));
+        assert_eq!(qvm.backend().name(), "stabilizer");
+        assert!(qvm.select_backend(Backend::Dense).is_err());
+        qvm.select_backend(Backend::Sparse).unwrap();
+        qvm.next();
+        let saved = qvm.snapshot();
+        qvm.prev();
+        assert_eq!(qvm.backend().amplitudes().unwrap().len(), 1);
+        qvm.restore(&saved).unwrap();
+        assert_eq!(qvm.counter, 1);
+        assert_eq!(qvm.backend().amplitudes().unwrap().len(), 2);
+        assert!(qvm.update("h 12\nh 12\n"));
+        assert!(qvm.restore(&saved).is_err());
+        // the watches and conditions follow the restored state
+        assert!(qvm.update("h 0\nx 1\n"));
+        qvm.set_watches("P(1)").unwrap();
+        qvm.set_conditions("P(1) changes").unwrap();
+        qvm.reset();
+        qvm.next();
+        let saved = qvm.snapshot();
+        qvm.next();
+        assert!((qvm.watched[0].clone().unwrap().re - 1.0).abs() < TOL);
+        assert_eq!(qvm.triggered, vec![0]);
+        qvm.restore(&saved).unwrap();
+        assert!(qvm.watched[0].clone().unwrap().re.abs() < TOL);
+        assert!(qvm.triggered.is_empty());
+    }
+    #[test]
+    fn impossible_outcome() {
+        // reading 1 from |0> leaves the state alone rather than dividing by zero
+        let mut dense = Dense::new(2);
+        dense.collapse(0, true);
+        assert!((dense.norm() - 1.0).abs() < 1e-6);
+        let mut sparse = SparseState::new(2);
+        sparse.collapse(0, true);
+        assert!((sparse.norm() - 1.0).abs() < 1e-6);
+    }
+}
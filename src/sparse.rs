@@ -0,0 +1,158 @@
+// Sparse state vectors, a map from basis index to amplitude, for wide states
+// with only a few non-zero entries such as ghz states or basis arithmetic.
+use float::Float;
+use qvm::{deposit, is_zero, Complex, Matrix, C0, C1};
+use simulator::Simulator;
+use std::collections::BTreeMap;
+
+pub type Sparse = BTreeMap<usize, Complex>;
+
+#[derive(Clone)]
+pub struct SparseState {
+    width: usize,
+    entries: Sparse,
+}
+
+impl SparseState {
+    pub fn new(width: usize) -> SparseState {
+        SparseState { width, entries: zero() }
+    }
+}
+
+impl Simulator for SparseState {
+    fn name(&self) -> &'static str {
+        "sparse"
+    }
+    fn width(&self) -> usize {
+        self.width
+    }
+    fn apply(&mut self, _: &str, gate: &Matrix, qubits: &[usize]) -> Float {
+        self.entries = apply_gate(gate, qubits, &self.entries);
+        0.0
+    }
+    fn probability(&mut self, qb: usize) -> Float {
+        probability(&self.entries, qb)
+    }
+    fn collapse(&mut self, qb: usize, outcome: bool) {
+        self.entries = collapse(&self.entries, qb, outcome);
+    }
+    fn reset(&mut self) {
+        self.entries = zero();
+    }
+    fn amplitude(&self, n: usize) -> Option<Complex> {
+        Some(self.entries.get(&n).cloned().unwrap_or(C0))
+    }
+    fn rescale(&mut self, factor: Float) {
+        for c in self.entries.values_mut() {
+            *c = *c * factor;
+        }
+    }
+    fn amplitudes(&self) -> Option<Vec<(usize, Complex)>> {
+        Some(self.entries.iter().map(|(&n, &c)| (n, c)).collect())
+    }
+    fn snapshot(&self) -> Box<dyn Simulator> {
+        Box::new(self.clone())
+    }
+    fn status(&self) -> String {
+        format!("{} non-zero amplitudes", self.entries.len())
+    }
+}
+
+pub fn zero() -> Sparse {
+    let mut ret = Sparse::new();
+    ret.insert(0, C1);
+    ret
+}
+
+// like the dense apply_gate, but each non-zero entry only feeds the 2^k
+// entries that share its untouched qubits
+pub fn apply_gate(gate: &Matrix, qubits: &[usize], state: &Sparse) -> Sparse {
+    let k = qubits.len();
+    let offsets: Vec<usize> = (0..1 << k).map(|local| deposit(local, qubits)).collect();
+    let mask = offsets[(1 << k) - 1];
+    let mut ret = Sparse::new();
+    for (&n, &amp) in state {
+        let col = (0..k).fold(0, |acc, j| acc << 1 | (n >> qubits[j] & 1));
+        for (row, offset) in offsets.iter().enumerate() {
+            let item = gate[row][col];
+            if !is_zero(item) {
+                *ret.entry(n & !mask | offset).or_insert(C0) += item * amp;
+            }
+        }
+    }
+    ret.into_iter().filter(|&(_, c)| !is_zero(c)).collect()
+}
+
+// the probability of reading 1 from `qb`
+pub fn probability(state: &Sparse, qb: usize) -> Float {
+    state.iter().filter(|&(n, _)| n >> qb & 1 == 1).map(|(_, c)| c.norm_sqr()).sum()
+}
+
+pub fn collapse(state: &Sparse, qb: usize, outcome: bool) -> Sparse {
+    let kept: Sparse = state
+        .iter()
+        .filter(|&(n, _)| (n >> qb & 1 == 1) == outcome)
+        .map(|(&n, &c)| (n, c))
+        .collect();
+    let norm = kept.values().map(|c| c.norm_sqr()).sum::<Float>().sqrt();
+    if norm <= 0.0 {
+        return state.clone();
+    }
+    kept.into_iter().map(|(n, c)| (n, c / norm)).collect()
+}
+
+pub fn fmt_ket(n: usize, width: usize) -> String {
+    format!("|{:0width$b}>", n, width = width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qvm::QVM;
+
+    #[test]
+    fn matches_dense() {
+        let prog = "h 0\nt 0\ncnot 0 2\nry(0.3) 1\nctrl @ h 1 0\nswap 2 1\ny 2\n";
+        let mut qvm = QVM::new();
+        assert!(qvm.update(prog));
+        let mut state = zero();
+        for inst in qvm.program.clone() {
+            let (expr, words) = inst.parts();
+            let qubits: Vec<usize> = words.iter().map(|qb| qb.parse().unwrap()).collect();
+            state = apply_gate(&qvm.resolve(expr).unwrap(), &qubits, &state);
+            qvm.next();
+        }
+        for (n, c) in qvm.state.iter().enumerate() {
+            let sparse = state.get(&n).cloned().unwrap_or(C0);
+            assert!((c - sparse).norm() < 1e-5, "{} vs {} at {}", c, sparse, n);
+        }
+    }
+    #[test]
+    fn wide_ghz() {
+        // t keeps it off the tableau, and 24 qubits keep it off the dense state
+        let mut prog = "h 0\nt 0\n".to_string();
+        for q in 0..23 {
+            prog += &format!("cnot {} {}\n", q, q + 1);
+        }
+        prog += "x 23\nmeasure 5\n";
+        let mut qvm = QVM::new();
+        assert!(qvm.update(&prog));
+        assert_eq!(qvm.backend().name(), "sparse");
+        while qvm.counter < qvm.program.len() - 1 {
+            qvm.next();
+        }
+        let state: Sparse = qvm.backend().amplitudes().unwrap().into_iter().collect();
+        assert_eq!(state.len(), 2);
+        let ones = (1 << 23) - 1;
+        assert!((probability(&state, 5) - 0.5).abs() < 1e-5);
+        assert_eq!(fmt_ket(*state.keys().next().unwrap(), 24), "|0".to_string() + &"1".repeat(23) + ">");
+        assert!(state.contains_key(&(1 << 23)));
+        qvm.next();
+        let state: Sparse = qvm.backend().amplitudes().unwrap().into_iter().collect();
+        assert_eq!(state.len(), 1);
+        let outcome = qvm.outcomes[qvm.program.len() - 1].unwrap();
+        let n = if outcome { ones } else { 1 << 23 };
+        assert!((state[&n].norm() - 1.0).abs() < 1e-5);
+        assert!(!qvm.update("h 30\nt 30\n"));
+    }
+}
@@ -0,0 +1,305 @@
+// Aaronson-Gottesman (CHP) tableau for clifford circuits. Rows 0..n are the
+// destabilizers and rows n..2n the stabilizers, each a signed pauli string with
+// x and z bits per qubit; row 2n is scratch space for measurements.
+use float::Float;
+use qvm::{Complex, Matrix, NQ};
+use simulator::{Dense, Simulator};
+
+#[derive(Clone)]
+pub struct Tableau {
+    n: usize,
+    x: Vec<Vec<bool>>,
+    z: Vec<Vec<bool>>,
+    r: Vec<bool>,
+    // The same state as a vector on up to NQ qubits, stepped along with the
+    // tableau, which drops the global phase the dense backend keeps.
+    dense: Option<Dense>,
+}
+
+// qubits taken by each gate the tableau can run
+pub fn arity(name: &str) -> Option<usize> {
+    match name {
+        "h" | "s" | "x" | "y" | "z" | "i1" => Some(1),
+        "cnot" | "cz" | "swap" => Some(2),
+        _ => None,
+    }
+}
+
+impl Tableau {
+    // |0..0>, stabilized by Z on every qubit
+    pub fn new(n: usize) -> Tableau {
+        let mut x = vec![vec![false; n]; 2 * n + 1];
+        let mut z = vec![vec![false; n]; 2 * n + 1];
+        for i in 0..n {
+            x[i][i] = true;
+            z[n + i][i] = true;
+        }
+        Tableau {
+            n,
+            x,
+            z,
+            r: vec![false; 2 * n + 1],
+            dense: if n <= NQ { Some(Dense::new(n)) } else { None },
+        }
+    }
+    pub fn apply(&mut self, name: &str, qubits: &[usize]) {
+        match name {
+            "h" => self.h(qubits[0]),
+            "s" => self.s(qubits[0]),
+            "x" => self.pauli(qubits[0], false, true),
+            "y" => self.pauli(qubits[0], true, true),
+            "z" => self.pauli(qubits[0], true, false),
+            "i1" => {}
+            "cnot" => self.cnot(qubits[0], qubits[1]),
+            "cz" => {
+                self.h(qubits[1]);
+                self.cnot(qubits[0], qubits[1]);
+                self.h(qubits[1]);
+            }
+            "swap" => {
+                for i in 0..2 * self.n {
+                    self.x[i].swap(qubits[0], qubits[1]);
+                    self.z[i].swap(qubits[0], qubits[1]);
+                }
+            }
+            _ => unreachable!("not a clifford gate"),
+        }
+    }
+    fn h(&mut self, a: usize) {
+        for i in 0..2 * self.n {
+            self.r[i] ^= self.x[i][a] && self.z[i][a];
+            let x = self.x[i][a];
+            self.x[i][a] = self.z[i][a];
+            self.z[i][a] = x;
+        }
+    }
+    fn s(&mut self, a: usize) {
+        for i in 0..2 * self.n {
+            self.r[i] ^= self.x[i][a] && self.z[i][a];
+            self.z[i][a] ^= self.x[i][a];
+        }
+    }
+    fn cnot(&mut self, a: usize, b: usize) {
+        for i in 0..2 * self.n {
+            self.r[i] ^= self.x[i][a] && self.z[i][b] && !(self.x[i][b] ^ self.z[i][a]);
+            self.x[i][b] ^= self.x[i][a];
+            self.z[i][a] ^= self.z[i][b];
+        }
+    }
+    fn pauli(&mut self, a: usize, flips_x: bool, flips_z: bool) {
+        // a pauli only flips the signs of the rows it anticommutes with
+        for i in 0..2 * self.n {
+            self.r[i] ^= (flips_x && self.x[i][a]) ^ (flips_z && self.z[i][a]);
+        }
+    }
+    // row h = row i * row h, keeping track of the phase
+    fn rowsum(&mut self, h: usize, i: usize) {
+        let mut sum: i32 = 2 * (self.r[h] as i32 + self.r[i] as i32);
+        for j in 0..self.n {
+            let (x1, z1, x2, z2) = (self.x[i][j], self.z[i][j], self.x[h][j], self.z[h][j]);
+            sum += match (x1, z1) {
+                (false, false) => 0,
+                (true, true) => z2 as i32 - x2 as i32,
+                (true, false) => z2 as i32 * (2 * x2 as i32 - 1),
+                (false, true) => x2 as i32 * (1 - 2 * z2 as i32),
+            };
+            self.x[h][j] ^= x1;
+            self.z[h][j] ^= z1;
+        }
+        self.r[h] = sum.rem_euclid(4) == 2;
+    }
+    // the outcome of measuring qubit a, or None when it is random
+    fn deterministic(&mut self, a: usize) -> Option<bool> {
+        let n = self.n;
+        if (n..2 * n).any(|p| self.x[p][a]) {
+            return None;
+        }
+        let scratch = 2 * n;
+        self.x[scratch] = vec![false; n];
+        self.z[scratch] = vec![false; n];
+        self.r[scratch] = false;
+        for i in 0..n {
+            if self.x[i][a] {
+                self.rowsum(scratch, i + n);
+            }
+        }
+        Some(self.r[scratch])
+    }
+    // Measures qubit a in the z basis, taking `coin` as the outcome if it is random.
+    pub fn measure(&mut self, a: usize, coin: bool) -> bool {
+        let n = self.n;
+        match (n..2 * n).find(|&p| self.x[p][a]) {
+            Some(p) => {
+                for i in 0..2 * n {
+                    if i != p && self.x[i][a] {
+                        self.rowsum(i, p);
+                    }
+                }
+                self.x[p - n] = self.x[p].clone();
+                self.z[p - n] = self.z[p].clone();
+                self.r[p - n] = self.r[p];
+                self.x[p] = vec![false; n];
+                self.z[p] = vec![false; n];
+                self.z[p][a] = true;
+                self.r[p] = coin;
+                coin
+            }
+            None => self.deterministic(a).unwrap(),
+        }
+    }
+    // the stabilizer generators in ket order, eg. "+XX" and "+ZZ" for a bell pair
+    pub fn generators(&self) -> Vec<String> {
+        (self.n..2 * self.n)
+            .map(|i| {
+                let sign = if self.r[i] { '-' } else { '+' };
+                let paulis = (0..self.n).rev().map(|q| match (self.x[i][q], self.z[i][q]) {
+                    (false, false) => 'I',
+                    (true, false) => 'X',
+                    (false, true) => 'Z',
+                    (true, true) => 'Y',
+                });
+                ::std::iter::once(sign).chain(paulis).collect()
+            })
+            .collect()
+    }
+}
+
+impl Simulator for Tableau {
+    fn name(&self) -> &'static str {
+        "stabilizer"
+    }
+    fn width(&self) -> usize {
+        self.n
+    }
+    fn apply(&mut self, expr: &str, gate: &Matrix, qubits: &[usize]) -> Float {
+        Tableau::apply(self, expr, qubits);
+        if let Some(ref mut dense) = self.dense {
+            dense.apply(expr, gate, qubits);
+        }
+        0.0
+    }
+    fn reversible(&self) -> bool {
+        false
+    }
+    fn probability(&mut self, qb: usize) -> Float {
+        match self.deterministic(qb) {
+            Some(outcome) => outcome as u8 as Float,
+            None => 0.5,
+        }
+    }
+    fn collapse(&mut self, qb: usize, outcome: bool) {
+        let outcome = self.measure(qb, outcome);
+        if let Some(ref mut dense) = self.dense {
+            dense.collapse(qb, outcome);
+        }
+    }
+    fn reset(&mut self) {
+        *self = Tableau::new(self.n);
+    }
+    fn amplitude(&self, n: usize) -> Option<Complex> {
+        self.dense.as_ref().and_then(|dense| dense.amplitude(n))
+    }
+    // a tableau only holds normalised states
+    fn norm(&self) -> Float {
+        1.0
+    }
+    fn rescale(&mut self, _: Float) {}
+    // only up to NQ qubits, where the vector is kept
+    fn amplitudes(&self) -> Option<Vec<(usize, Complex)>> {
+        self.dense.as_ref().and_then(|dense| dense.amplitudes())
+    }
+    fn snapshot(&self) -> Box<dyn Simulator> {
+        Box::new(self.clone())
+    }
+    fn generators(&self) -> Option<Vec<String>> {
+        Some(Tableau::generators(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qvm::QVM;
+    use simulator::Backend;
+
+    #[test]
+    fn bell_generators() {
+        let mut t = Tableau::new(2);
+        t.apply("h", &[0]);
+        t.apply("cnot", &[0, 1]);
+        let mut gens = t.generators();
+        gens.sort();
+        assert_eq!(gens, vec!["+XX", "+ZZ"]);
+        t.apply("y", &[1]);
+        let mut gens = t.generators();
+        gens.sort();
+        assert_eq!(gens, vec!["-XX", "-ZZ"]);
+        // the second measurement always agrees with the first
+        let first = t.measure(0, true);
+        assert_eq!(t.measure(1, false), !first);
+    }
+    #[test]
+    fn wide_ghz() {
+        let mut prog = "h 0\n".to_string();
+        for q in 0..199 {
+            prog += &format!("cnot {} {}\n", q, q + 1);
+        }
+        for q in 0..200 {
+            prog += &format!("measure {}\n", q);
+        }
+        let mut qvm = QVM::new();
+        assert!(qvm.update(&prog));
+        assert_eq!((qvm.backend().name(), qvm.backend().width()), ("stabilizer", 200));
+        while qvm.counter < qvm.program.len() {
+            qvm.next();
+        }
+        let outcomes: Vec<bool> = qvm.outcomes.iter().filter_map(|&o| o).collect();
+        assert_eq!(outcomes.len(), 200);
+        assert!(outcomes.iter().all(|&o| o == outcomes[0]));
+        // too wide for anything but the tableau
+        assert!(!qvm.update("h 0\nt 0\ncnot 0 100\n"));
+    }
+    #[test]
+    fn agrees_with_dense() {
+        let mut qvm = QVM::new();
+        qvm.select_backend(Backend::Stabilizer).unwrap();
+        assert!(qvm.update("x 0\nh 1\ns 1\ncz 1 2\nswap 0 2\ncnot 2 3\nmeasure 3\nmeasure 0\n"));
+        while qvm.counter < qvm.program.len() {
+            qvm.next();
+        }
+        assert_eq!(qvm.outcomes[6], Some(true));
+        assert_eq!(qvm.outcomes[7], Some(false));
+        // the collapsed dense state is the one stabilized by the tableau
+        for gen in qvm.backend().generators().unwrap() {
+            let sign = if gen.starts_with('-') { -1.0 } else { 1.0 };
+            let value = qvm.expectation(&gen[1..]).unwrap();
+            assert!((value - sign).abs() < 1e-5, "{} gives {}", gen, value);
+        }
+        // stepping back over a measurement replays it
+        qvm.prev();
+        qvm.prev();
+        qvm.next();
+        assert_eq!(qvm.outcomes[6], Some(true));
+    }
+    #[test]
+    fn same_phase() {
+        // y and s carry a global phase the tableau alone would drop
+        let prog = "h 0\ns 0\ny 1\ncz 0 1\ns 1\nmeasure 1\n";
+        let mut amplitudes = Vec::new();
+        for &backend in &[Backend::Dense, Backend::Stabilizer] {
+            let mut qvm = QVM::new();
+            qvm.select_backend(backend).unwrap();
+            assert!(qvm.update(prog));
+            while qvm.counter < qvm.program.len() {
+                qvm.next();
+            }
+            assert_eq!(qvm.backend().name(), if backend == Backend::Dense { "dense" } else { "stabilizer" });
+            amplitudes.push(qvm.backend().amplitudes().unwrap());
+        }
+        assert_eq!(amplitudes[0].len(), amplitudes[1].len());
+        for (&(n, a), &(m, b)) in amplitudes[0].iter().zip(amplitudes[1].iter()) {
+            assert_eq!(n, m);
+            assert!((a - b).norm() < 1e-5, "{} vs {} at {}", a, b, n);
+        }
+    }
+}
@@ -0,0 +1,375 @@
+// Lowering gates onto a fixed basis such as {cnot, rz, sx, x} or {cz, rx, ry}.
+// Two qubit gates go through the kak circuit, larger ones through two level
+// unitaries and multi controlled gates (Barenco et al.).
+use float::{Float, ZERO_TOL};
+use float::consts::{FRAC_PI_2, PI};
+use kak::{self, Step};
+use linalg::{self, adjoint, eig_normal, euler_zyz, identity, phase_between};
+use qvm::{controlled, mul, Complex, Matrix, C0, C1, TOL};
+use std::collections::BTreeMap;
+
+pub struct Basis {
+    gates: BTreeMap<String, Matrix>,
+    rotations: Vec<String>,
+}
+
+// names separated by spaces or commas, braces optional: "{cnot, rz, sx, x}"
+pub fn parse_basis(text: &str, known: &BTreeMap<String, Matrix>) -> Result<Basis, String> {
+    let mut basis = Basis {
+        gates: BTreeMap::new(),
+        rotations: Vec::new(),
+    };
+    let words = text.split(|c: char| c.is_whitespace() || c == ',' || c == '{' || c == '}');
+    for name in words.filter(|w| !w.is_empty()) {
+        match name {
+            "rx" | "ry" | "rz" => basis.rotations.push(name.to_string()),
+            _ => match known.get(name) {
+                Some(gate) => {
+                    basis.gates.insert(name.to_string(), gate.clone());
+                }
+                None => return Err(format!("unknown gate '{}' in basis", name)),
+            },
+        }
+    }
+    if basis.gates.is_empty() && basis.rotations.is_empty() {
+        return Err("empty basis".into());
+    }
+    Ok(basis)
+}
+
+impl Basis {
+    // whether an instruction's gate expression is already in the basis
+    pub fn contains(&self, expr: &str) -> bool {
+        if self.gates.contains_key(expr) {
+            return true;
+        }
+        let axis = expr.splitn(2, '(').next().unwrap();
+        expr.ends_with(')') && !expr.contains(' ') && self.has(axis)
+    }
+    fn has(&self, axis: &str) -> bool {
+        self.rotations.iter().any(|r| r == axis)
+    }
+}
+
+fn rotate(axis: &str, turn: Float, qb: usize, lines: &mut Vec<String>) {
+    // a full turn is only a global phase
+    let wrapped = turn - 2.0 * PI * (turn / (2.0 * PI)).round();
+    if wrapped.abs() > ZERO_TOL {
+        lines.push(format!("{}({}) {}", axis, turn, qb));
+    }
+}
+
+fn hadamard() -> Matrix {
+    let h = Complex::new(1.0 / (2.0 as Float).sqrt(), 0.0);
+    vec![vec![h, h], vec![h, -h]]
+}
+
+fn pauli_x() -> Matrix {
+    vec![vec![C0, C1], vec![C1, C0]]
+}
+
+fn single(u: &Matrix, qb: usize, basis: &Basis, lines: &mut Vec<String>) -> Result<(), String> {
+    if phase_between(&identity(2), u, TOL).is_some() {
+        return Ok(());
+    }
+    for (name, gate) in &basis.gates {
+        if gate.len() == 2 && phase_between(gate, u, TOL).is_some() {
+            lines.push(format!("{} {}", name, qb));
+            return Ok(());
+        }
+    }
+    // u = rz(phi) ry(theta) rz(lambda) up to phase, with ry or rz swapped for
+    // a conjugated rotation when the basis lacks it
+    let e = euler_zyz(u);
+    let flat = e.theta.abs() < ZERO_TOL;
+    if basis.has("rz") && (flat || basis.has("ry")) {
+        if flat {
+            rotate("rz", e.lambda + e.phi, qb, lines);
+        } else {
+            rotate("rz", e.lambda, qb, lines);
+            rotate("ry", e.theta, qb, lines);
+            rotate("rz", e.phi, qb, lines);
+        }
+    } else if basis.has("rz") && basis.has("rx") {
+        rotate("rz", e.lambda - FRAC_PI_2, qb, lines);
+        rotate("rx", e.theta, qb, lines);
+        rotate("rz", e.phi + FRAC_PI_2, qb, lines);
+    } else if basis.has("rz") && basis.gates.contains_key("sx") {
+        rotate("rz", e.lambda, qb, lines);
+        lines.push(format!("sx {}", qb));
+        rotate("rz", e.theta + PI, qb, lines);
+        lines.push(format!("sx {}", qb));
+        rotate("rz", e.phi + PI, qb, lines);
+    } else if basis.has("rx") && basis.has("ry") {
+        rotate("ry", FRAC_PI_2, qb, lines);
+        if flat {
+            rotate("rx", e.lambda + e.phi, qb, lines);
+        } else {
+            rotate("rx", e.lambda, qb, lines);
+            rotate("ry", e.theta, qb, lines);
+            rotate("rx", e.phi, qb, lines);
+        }
+        rotate("ry", -FRAC_PI_2, qb, lines);
+    } else {
+        return Err("basis cannot express every single qubit gate".into());
+    }
+    Ok(())
+}
+
+fn entangle(control: usize, target: usize, basis: &Basis, lines: &mut Vec<String>) -> Result<(), String> {
+    if basis.gates.contains_key("cnot") {
+        lines.push(format!("cnot {} {}", control, target));
+    } else if basis.gates.contains_key("cz") {
+        single(&hadamard(), target, basis, lines)?;
+        lines.push(format!("cz {} {}", control, target));
+        single(&hadamard(), target, basis, lines)?;
+    } else {
+        return Err("basis needs cnot or cz for two qubit gates".into());
+    }
+    Ok(())
+}
+
+fn two(u: &Matrix, a: usize, b: usize, basis: &Basis, lines: &mut Vec<String>) -> Result<(), String> {
+    // the same gate with its operands exchanged
+    let flip = |i: usize| (i & 1) << 1 | i >> 1;
+    let swapped: Matrix = (0..4).map(|i| (0..4).map(|j| u[flip(i)][flip(j)]).collect()).collect();
+    for (name, gate) in &basis.gates {
+        if gate.len() != 4 {
+            continue;
+        }
+        if phase_between(gate, u, TOL).is_some() {
+            lines.push(format!("{} {} {}", name, a, b));
+            return Ok(());
+        }
+        if phase_between(gate, &swapped, TOL).is_some() {
+            lines.push(format!("{} {} {}", name, b, a));
+            return Ok(());
+        }
+    }
+    let (first, second) = kak::split_kron(u);
+    if phase_between(&kak::kron(&first, &second), u, TOL).is_some() {
+        single(&first, a, basis, lines)?;
+        return single(&second, b, basis, lines);
+    }
+    let cnot = controlled(&pauli_x());
+    if phase_between(&cnot, u, TOL).is_some() {
+        return entangle(a, b, basis, lines);
+    }
+    if phase_between(&cnot, &swapped, TOL).is_some() {
+        return entangle(b, a, basis, lines);
+    }
+    let qubits = [a, b];
+    for step in kak::circuit(u) {
+        match step {
+            Step::Local(gate, q) => single(&gate, qubits[q], basis, lines)?,
+            Step::Rz(turn, q) if basis.has("rz") => rotate("rz", turn, qubits[q], lines),
+            Step::Rz(turn, q) => single(&linalg::rz(turn), qubits[q], basis, lines)?,
+            Step::Ry(turn, q) if basis.has("ry") => rotate("ry", turn, qubits[q], lines),
+            Step::Ry(turn, q) => single(&linalg::ry(turn), qubits[q], basis, lines)?,
+            Step::Cnot(c, t) => entangle(qubits[c], qubits[t], basis, lines)?,
+        }
+    }
+    Ok(())
+}
+
+fn sqrt_unitary(u: &Matrix) -> Matrix {
+    let (values, v) = eig_normal(u);
+    let dim = u.len();
+    let mut root = vec![vec![C0; dim]; dim];
+    for (k, value) in values.iter().enumerate() {
+        root[k][k] = value.sqrt();
+    }
+    mul(&mul(&v, &root), &adjoint(&v))
+}
+
+fn multi_controlled(
+    controls: &[usize],
+    target: usize,
+    g: &Matrix,
+    basis: &Basis,
+    lines: &mut Vec<String>,
+) -> Result<(), String> {
+    // with v^2 = g: c(v) on the last control, flip it with the others,
+    // c(v^dagger), flip back, then v controlled by the others
+    match controls.len() {
+        0 => single(g, target, basis, lines),
+        1 => two(&controlled(g), controls[0], target, basis, lines),
+        n => {
+            let v = sqrt_unitary(g);
+            let (last, rest) = (controls[n - 1], &controls[..n - 1]);
+            multi_controlled(&[last], target, &v, basis, lines)?;
+            multi_controlled(rest, last, &pauli_x(), basis, lines)?;
+            multi_controlled(&[last], target, &adjoint(&v), basis, lines)?;
+            multi_controlled(rest, last, &pauli_x(), basis, lines)?;
+            multi_controlled(rest, target, &v, basis, lines)
+        }
+    }
+}
+
+// Two level unitaries g on basis states (s, t) with g_m .. g_1 u = 1.
+fn two_level(u: &Matrix) -> Vec<(usize, usize, Matrix)> {
+    let dim = u.len();
+    let mut w = u.clone();
+    let mut ret = Vec::new();
+    for j in 0..dim - 1 {
+        for i in j + 1..dim {
+            let (x, y) = (w[j][j], w[i][j]);
+            if y.norm() < ZERO_TOL {
+                continue;
+            }
+            let n = (x.norm_sqr() + y.norm_sqr()).sqrt();
+            ret.push((j, i, vec![vec![x.conj() / n, y.conj() / n], vec![-y / n, x / n]]));
+            mix_rows(&mut w, &ret[ret.len() - 1]);
+        }
+    }
+    // w is diagonal now, clear its phases one state at a time
+    for j in 0..dim {
+        let phase = w[j][j].conj();
+        if (phase - C1).norm() > ZERO_TOL {
+            ret.push(if j + 1 < dim {
+                (j, j + 1, vec![vec![phase, C0], vec![C0, C1]])
+            } else {
+                (j - 1, j, vec![vec![C1, C0], vec![C0, phase]])
+            });
+            mix_rows(&mut w, &ret[ret.len() - 1]);
+        }
+    }
+    ret
+}
+
+fn mix_rows(w: &mut Matrix, &(s, t, ref g): &(usize, usize, Matrix)) {
+    for col in 0..w.len() {
+        let (p, q) = (w[s][col], w[t][col]);
+        w[s][col] = g[0][0] * p + g[0][1] * q;
+        w[t][col] = g[1][0] * p + g[1][1] * q;
+    }
+}
+
+fn two_level_gate(
+    s: usize,
+    t: usize,
+    g: &Matrix,
+    qubits: &[usize],
+    basis: &Basis,
+    lines: &mut Vec<String>,
+) -> Result<(), String> {
+    // walk s towards t one bit at a time (a gray code), so the gate itself only
+    // needs to act on one qubit, controlled by all the others
+    let k = qubits.len();
+    let qubit = |bit: usize| qubits[k - 1 - bit];
+    let bits: Vec<usize> = (0..k).filter(|&bit| (s ^ t) >> bit & 1 == 1).collect();
+    let mut path = vec![s];
+    for &bit in &bits[..bits.len() - 1] {
+        let next = path[path.len() - 1] ^ 1 << bit;
+        path.push(next);
+    }
+    let controlled_on = |state: usize, target: usize, g: &Matrix, lines: &mut Vec<String>| -> Result<(), String> {
+        // controls that should be |0> are wrapped in x
+        let others: Vec<usize> = (0..k).filter(|&bit| bit != target).collect();
+        let zeros: Vec<usize> = others.iter().filter(|&&bit| state >> bit & 1 == 0).map(|&bit| qubit(bit)).collect();
+        for &qb in &zeros {
+            single(&pauli_x(), qb, basis, lines)?;
+        }
+        let controls: Vec<usize> = others.iter().map(|&bit| qubit(bit)).collect();
+        multi_controlled(&controls, qubit(target), g, basis, lines)?;
+        for &qb in &zeros {
+            single(&pauli_x(), qb, basis, lines)?;
+        }
+        Ok(())
+    };
+    let flips: Vec<(usize, usize)> = path.windows(2).map(|w| (w[0], (w[0] ^ w[1]).trailing_zeros() as usize)).collect();
+    for &(state, bit) in &flips {
+        controlled_on(state, bit, &pauli_x(), lines)?;
+    }
+    let (near, bit) = (path[path.len() - 1], bits[bits.len() - 1]);
+    let core = if near >> bit & 1 == 0 {
+        g.clone()
+    } else {
+        mul(&mul(&pauli_x(), g), &pauli_x())
+    };
+    controlled_on(near, bit, &core, lines)?;
+    for &(state, bit) in flips.iter().rev() {
+        controlled_on(state, bit, &pauli_x(), lines)?;
+    }
+    Ok(())
+}
+
+fn many(u: &Matrix, qubits: &[usize], basis: &Basis, lines: &mut Vec<String>) -> Result<(), String> {
+    let dim = u.len();
+    let id = identity(dim);
+    // a gate controlled by every qubit but the last, eg. a toffoli
+    let is_controlled = (0..dim).all(|i| {
+        (0..dim).all(|j| (i >= dim - 2 && j >= dim - 2) || (u[i][j] - id[i][j]).norm() < TOL)
+    });
+    if is_controlled {
+        let block = vec![
+            vec![u[dim - 2][dim - 2], u[dim - 2][dim - 1]],
+            vec![u[dim - 1][dim - 2], u[dim - 1][dim - 1]],
+        ];
+        let (target, controls) = qubits.split_last().unwrap();
+        return multi_controlled(controls, *target, &block, basis, lines);
+    }
+    // u = g_1^dagger .. g_m^dagger, so g_m^dagger runs first
+    for (s, t, g) in two_level(u).iter().rev() {
+        two_level_gate(*s, *t, &adjoint(g), qubits, basis, lines)?;
+    }
+    Ok(())
+}
+
+// Program lines equal to `gate` on `qubits` up to global phase, using only the basis.
+pub fn lower(gate: &Matrix, qubits: &[usize], basis: &Basis) -> Result<Vec<String>, String> {
+    let mut lines = Vec::new();
+    match qubits.len() {
+        1 => single(gate, qubits[0], basis, &mut lines)?,
+        2 => two(gate, qubits[0], qubits[1], basis, &mut lines)?,
+        _ => many(gate, qubits, basis, &mut lines)?,
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use qvm::{Equivalence, QVM};
+
+    const PROGRAM: &str = "h 0
+t 1
+y 2
+rz(0.3) 0
+cnot 0 1
+swap 1 2
+ctrl @ s 2 0
+ctrl @ ctrl @ x 0 1 2
+ctrl @ swap 2 0 1
+";
+
+    fn lowered(basis: &str) -> Result<Vec<String>, String> {
+        let mut qvm = QVM::new();
+        assert!(qvm.update(PROGRAM));
+        let out = qvm.transpile(basis)?;
+        match qvm.compare(&out).unwrap() {
+            Equivalence::Same { .. } => {}
+            Equivalence::Differs { input, .. } => panic!("{} differs on input {}", basis, input),
+        }
+        Ok(out.iter().map(|inst| inst.parts().0.to_string()).collect())
+    }
+    #[test]
+    fn bases() {
+        for (basis, allowed) in &[
+            ("cnot rz sx x", vec!["cnot", "rz", "sx", "x"]),
+            ("{cz, rx, ry}", vec!["cz", "rx", "ry"]),
+            ("cnot, rx, rz", vec!["cnot", "rx", "rz"]),
+            ("cz ry rz h", vec!["cz", "ry", "rz", "h"]),
+        ] {
+            for name in lowered(basis).unwrap() {
+                let family = name.splitn(2, '(').next().unwrap();
+                assert!(allowed.contains(&family), "{} not in {}", name, basis);
+            }
+        }
+    }
+    #[test]
+    fn unsupported() {
+        assert!(lowered("h t cnot").unwrap_err().contains("line 3"));
+        assert!(lowered("rz ry").unwrap_err().contains("cnot or cz"));
+        assert!(lowered("cnot rz nope").unwrap_err().contains("nope"));
+    }
+}